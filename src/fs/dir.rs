@@ -1,45 +1,648 @@
 
 use super::stream::Stream;
-use crate::fs::stream::{Seek, Read, Write};
+use super::fs::{u16_from_bytes, u32_from_bytes, u16_to_bytes, u32_to_bytes};
+use super::sector::{FsErr, CACHE_BLOCKS};
+use super::time::DateTime;
+use crate::fs::stream::Read;
 
-struct DirIterator<'stream, 'bd: 'stream> {
-    stream: Stream<'bd, 'stream>,
+pub struct DirIterator<'stream, 'bd: 'stream, const N: usize = CACHE_BLOCKS> {
+    stream: Stream<'stream, 'bd, N>,
+    index: u32,
+    lfn: LongNameBuilder,
 }
 
-const ATTR_LONG_FILE_NAME: u8 = 0x0f;
+impl <'stream, 'bd: 'stream, const N: usize> DirIterator<'stream, 'bd, N> {
+    pub fn new(stream: Stream<'stream, 'bd, N>) -> Self {
+        Self { stream, index: 0, lfn: LongNameBuilder::new() }
+    }
+
+    /// First cluster of the directory, 0 for the FAT12/16 root directory.
+    pub fn cluster(&self) -> u32 {
+        self.stream.first_cluster()
+    }
+
+    /// The entry called `name`, compared like `DirEntry::compare` does.
+    pub fn find_name(&mut self, name: &str) -> Option<DirEntry> {
+        self.find_map(|entry| if entry.compare(name) { Some(entry) } else { None })
+    }
+}
+
+/// Attribute byte of a directory entry.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Attributes(u8);
+
+impl Attributes {
+    pub const READ_ONLY: Self = Self(0x01);
+    pub const HIDDEN: Self = Self(0x02);
+    pub const SYSTEM: Self = Self(0x04);
+    pub const VOLUME_ID: Self = Self(0x08);
+    pub const DIRECTORY: Self = Self(0x10);
+    pub const ARCHIVE: Self = Self(0x20);
+    /// All four low bits together mark a long name entry.
+    pub const LONG_NAME: Self = Self(0x0f);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl core::ops::BitOr for Attributes {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitOrAssign for Attributes {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+/// Attributes, size and times of a file or directory.
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    pub attributes: Attributes,
+    pub size: u32,
+    pub created: Option<DateTime>,
+    pub modified: Option<DateTime>,
+    pub accessed: Option<DateTime>,
+}
+
+impl Metadata {
+    /// The root directory has no entry, only its attributes are known.
+    pub(crate) fn root() -> Self {
+        Self { attributes: Attributes::DIRECTORY, size: 0, created: None, modified: None, accessed: None }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes.contains(Attributes::DIRECTORY)
+    }
+}
+
+const ATTR_LONG_FILE_NAME: u8 = Attributes::LONG_NAME.bits();
+
+// a name starting with 0xE5 is stored with 0x05, 0xE5 marks deleted entries
+const SHORT_NAME_E5_ESCAPE: u8 = 0x05;
+
+const LFN_LAST: u8 = 0x40;
+const LFN_ORDINAL_MASK: u8 = 0x1f;
+const LFN_CHARS: usize = 13;
+const LFN_ENTRIES_MAX: usize = 20;
+// offsets of the 13 UCS-2 characters within a long name entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// NT uses these bits of byte 12 for 8.3 names written in lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+pub const LONG_NAME_MAX: usize = 255;
+// every UCS-2 character takes up to 3 bytes in UTF-8
+const NAME_BUFF_SIZE: usize = LONG_NAME_MAX * 3;
+
+/// Checksum of the 8.3 name stored in every long name entry of its run.
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name[..11].iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Collects the long name entries preceding a short entry. They are stored
+/// last part first, each one numbered with its ordinal.
+struct LongNameBuilder {
+    chars: [u16; LFN_ENTRIES_MAX * LFN_CHARS],
+    checksum: u8,
+    // ordinal of the next entry expected, 0 once the run is complete
+    expected: u8,
+    first_index: u32,
+    valid: bool,
+}
+
+impl LongNameBuilder {
+    fn new() -> Self {
+        Self {
+            chars: [0; LFN_ENTRIES_MAX * LFN_CHARS],
+            checksum: 0,
+            expected: 0,
+            first_index: 0,
+            valid: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.valid = false;
+    }
+
+    fn push(&mut self, index: u32, data: &[u8; 32]) {
+        let ordinal = data[0] & LFN_ORDINAL_MASK;
+
+        if ordinal == 0 || ordinal as usize > LFN_ENTRIES_MAX {
+            self.reset();
+            return;
+        }
+
+        if data[0] & LFN_LAST != 0 {
+            self.valid = true;
+            self.checksum = data[13];
+            self.first_index = index;
+        } else if !self.valid || ordinal != self.expected || data[13] != self.checksum {
+            self.reset();
+            return;
+        }
+
+        let start = (ordinal as usize - 1) * LFN_CHARS;
+
+        for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16_from_bytes(&data[offset..]);
+        }
+
+        if data[0] & LFN_LAST != 0 {
+            // anything after the last part is padding
+            let end = start + LFN_CHARS;
+            for c in self.chars[end..].iter_mut() {
+                *c = 0;
+            }
+        }
+
+        self.expected = ordinal - 1;
+    }
+
+    /// Long name for the short entry that closes the run, if the run is
+    /// complete and belongs to it.
+    fn take(&mut self, short_name: &[u8]) -> Option<(&[u16], u32)> {
+        if !self.valid || self.expected != 0 || self.checksum != lfn_checksum(short_name) {
+            self.reset();
+            return None;
+        }
+
+        self.valid = false;
+
+        let len = self.chars.iter().position(|&c| c == 0).unwrap_or(self.chars.len());
+
+        if len == 0 || len > LONG_NAME_MAX {
+            return None;
+        }
+
+        Some((&self.chars[..len], self.first_index))
+    }
+}
+
+/// 8.3 alias of a long name, built the way the FAT specification describes
+/// basis-name generation.
+pub struct ShortName {
+    basis: [u8; 11],
+    base_len: usize,
+    // the basis lost characters or was truncated, a numeric tail is needed
+    lossy: bool,
+    // the name is exactly the basis, no long name entries are needed
+    exact: bool,
+}
+
+impl ShortName {
+    pub fn new(name: &str) -> Result<Self, FsErr> {
+        let mut basis = [b' '; 11];
+        let mut lossy = false;
+        let mut exact = true;
+
+        // leading periods, and any spaces, are dropped
+        let trimmed = name.trim_start_matches('.');
+        let (base, ext) = match trimmed.rfind('.') {
+            Some(dot) => (&trimmed[..dot], &trimmed[(dot + 1)..]),
+            None => (trimmed, ""),
+        };
+
+        if trimmed.len() != name.len() {
+            lossy = true;
+        }
+
+        let mut fill = |part: &str, out: &mut [u8]| {
+            let mut len = 0;
+
+            for c in part.chars() {
+                if c == ' ' || c == '.' {
+                    lossy = true;
+                    continue;
+                }
+
+                if len == out.len() {
+                    lossy = true;
+                    break;
+                }
+
+                if c.is_ascii_lowercase() {
+                    exact = false;
+                }
+
+                out[len] = short_name_byte(c).unwrap_or_else(|| {
+                    lossy = true;
+                    b'_'
+                });
+                len += 1;
+            }
+
+            len
+        };
+
+        let base_len = fill(base, &mut basis[..8]);
+        let ext_len = fill(ext, &mut basis[8..]);
+
+        if base_len == 0 {
+            return Err(FsErr::InvalidName);
+        }
+
+        // "NAME." has no extension, but it isn't spelled as the basis either
+        if ext_len == 0 && trimmed.ends_with('.') {
+            lossy = true;
+        }
+
+        Ok(Self { basis, base_len, lossy, exact: exact && !lossy })
+    }
+
+    pub fn basis(&self) -> &[u8; 11] {
+        &self.basis
+    }
+
+    pub fn is_lossy(&self) -> bool {
+        self.lossy
+    }
+
+    pub fn is_exact(&self) -> bool {
+        self.exact
+    }
+
+    /// The basis with a `~n` numeric tail, the base shortened to make room.
+    pub fn with_tail(&self, n: u32) -> [u8; 11] {
+        let mut digits = [0u8; 10];
+        let mut digits_len = 0;
+        let mut n = n;
+
+        loop {
+            digits[digits_len] = b'0' + (n % 10) as u8;
+            digits_len += 1;
+            n /= 10;
+
+            if n == 0 {
+                break;
+            }
+        }
+
+        let mut name = self.basis;
+        let start = core::cmp::min(self.base_len, 8 - digits_len - 1);
+
+        for c in name[start..8].iter_mut() {
+            *c = b' ';
+        }
+
+        name[start] = b'~';
+
+        for (i, &digit) in digits[..digits_len].iter().rev().enumerate() {
+            name[start + 1 + i] = digit;
+        }
+
+        name
+    }
+
+    /// The `n` for which `with_tail(n)` spells `name`, if there is one.
+    pub fn tail_of(&self, name: &[u8; 11]) -> Option<u32> {
+        let tilde = name[..8].iter().rposition(|&c| c == b'~')?;
+        let digits = trim_spaces(&name[(tilde + 1)..8]);
+
+        if digits.is_empty() {
+            return None;
+        }
+
+        let n = digits.iter().try_fold(0u32, |n, &c| {
+            c.is_ascii_digit().then(|| n * 10 + u32::from(c - b'0'))
+        })?;
+
+        // leading zeros and a shorter base don't round trip
+        (n != 0 && self.with_tail(n) == *name).then_some(n)
+    }
+}
+
+pub(crate) fn short_name_byte(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        'A'..='Z' | '0'..='9' => Some(c as u8),
+        '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{' | '}' | '~' => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// Count of long name entries needed to store `name`.
+pub fn lfn_entries_count(name: &str) -> Result<u32, FsErr> {
+    let len = name.encode_utf16().count();
+
+    if len == 0 || len > LONG_NAME_MAX {
+        return Err(FsErr::InvalidName);
+    }
+
+    Ok(len.div_ceil(LFN_CHARS) as u32)
+}
+
+/// Long name entry `ordinal` (counting from 1) out of `count`, holding the
+/// matching 13 characters of `name`.
+pub fn lfn_entry(name: &str, ordinal: u32, count: u32, checksum: u8) -> [u8; 32] {
+    let mut data = [0u8; 32];
+    let start = (ordinal as usize - 1) * LFN_CHARS;
+    let mut chars = name.encode_utf16().skip(start);
+    let mut terminated = false;
+
+    for &offset in LFN_CHAR_OFFSETS.iter() {
+        // the name ends with a 0x0000 when there is room, the rest is 0xFFFF
+        let c = match chars.next() {
+            Some(c) => c,
+            None if !terminated => {
+                terminated = true;
+                0x0000
+            },
+            None => 0xFFFF,
+        };
+
+        u16_to_bytes(&mut data[offset..], c);
+    }
+
+    data[0] = ordinal as u8 | if ordinal == count { LFN_LAST } else { 0 };
+    data[11] = ATTR_LONG_FILE_NAME;
+    data[13] = checksum;
+    data
+}
+
+/// Checks a long name can be stored: no reserved characters, no control
+/// characters and no trailing spaces or periods, which Windows would drop.
+pub fn validate_name(name: &str) -> Result<(), FsErr> {
+    let reserved = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+
+    if name.is_empty() || name.ends_with('.') || name.ends_with(' ') || name.chars().any(reserved) {
+        return Err(FsErr::InvalidName);
+    }
+
+    lfn_entries_count(name).map(|_| ())
+}
+
+/// Long name entries share the attribute byte position with short ones.
+pub fn is_long_name_entry(data: &[u8; 32]) -> bool {
+    (data[11] & ATTR_LONG_FILE_NAME) == ATTR_LONG_FILE_NAME
+}
+
+pub fn entry_set_first_cluster(data: &mut [u8; 32], cluster: u32) {
+    u16_to_bytes(&mut data[20..], (cluster >> 16) as u16);
+    u16_to_bytes(&mut data[26..], cluster as u16);
+}
+
+pub fn entry_set_size(data: &mut [u8; 32], size: u32) {
+    u32_to_bytes(&mut data[28..], size);
+}
+
+/// Stamps the creation, modification and access times of a new entry.
+pub fn entry_set_created(data: &mut [u8; 32], now: DateTime) {
+    let (date, time, centis) = now.encode();
+    data[13] = centis;
+    u16_to_bytes(&mut data[14..], time);
+    u16_to_bytes(&mut data[16..], date);
+    entry_set_modified(data, now);
+}
+
+/// Stamps the modification time, a write is an access too.
+pub fn entry_set_modified(data: &mut [u8; 32], now: DateTime) {
+    let (date, time, _) = now.encode();
+    u16_to_bytes(&mut data[22..], time);
+    u16_to_bytes(&mut data[24..], date);
+    entry_set_accessed(data, now);
+}
+
+pub fn entry_set_accessed(data: &mut [u8; 32], now: DateTime) {
+    let (date, _, _) = now.encode();
+    u16_to_bytes(&mut data[18..], date);
+}
 
 pub struct DirEntry {
-    //void fat_get_file_modification_date(const struct fat_dir_entry_struct* dir_entry, uint16_t* year, uint8_t* month, uint8_t* day);
-//void fat_get_file_modification_time(const struct fat_dir_entry_struct* dir_entry, uint8_t* hour, uint8_t* min, uint8_t* sec);
     data: [u8; 32],
+    // 8.3 name with the 0xE5 escape undone
+    short_name: [u8; 11],
+    // where the entry lives: first cluster of its directory, slot index of
+    // the short entry and of the first long name entry
+    dir_cluster: u32,
+    index: u32,
+    lfn_index: u32,
+    // UTF-8, the long name or the 8.3 name when there is none
+    name: [u8; NAME_BUFF_SIZE],
+    name_len: usize,
+    long_name: bool,
 }
 
 impl DirEntry {
     pub fn new(data: [u8; 32]) -> Self {
-        for &d in data[..11].iter() {
-            /*
-            if (d >= b'0' && d <= b'9') || (d >= b'a' && d <= b'z') || (d >= b'A' && d <= b'Z') {
-                print!(" {} ", d as char);
-            } else {
-                print!("__ ");
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&data[..11]);
+
+        if short_name[0] == SHORT_NAME_E5_ESCAPE {
+            short_name[0] = 0xE5;
+        }
+
+        let mut entry = Self {
+            data,
+            short_name,
+            dir_cluster: 0,
+            index: 0,
+            lfn_index: 0,
+            name: [0; NAME_BUFF_SIZE],
+            name_len: 0,
+            long_name: false,
+        };
+        entry.set_short_name();
+        entry
+    }
+
+    fn push_name_char(&mut self, c: char) {
+        let len = c.encode_utf8(&mut self.name[self.name_len..]).len();
+        self.name_len += len;
+    }
+
+    fn set_short_name(&mut self) {
+        let data = self.data;
+        let short_name = self.short_name;
+        let base = trim_spaces(&short_name[..8]);
+        let ext = trim_spaces(&short_name[8..]);
+
+        self.name_len = 0;
+
+        for &c in base {
+            self.push_name_char(short_name_char(c, data[12] & NT_LOWER_BASE != 0));
+        }
+
+        if !ext.is_empty() {
+            self.push_name_char('.');
+
+            for &c in ext {
+                self.push_name_char(short_name_char(c, data[12] & NT_LOWER_EXT != 0));
             }
-            */
-            print!("{}", d as char);
         }
-        println!();
-        Self { data }
+    }
+
+    fn set_long_name(&mut self, chars: &[u16], lfn_index: u32) {
+        self.name_len = 0;
+
+        for c in core::char::decode_utf16(chars.iter().cloned()) {
+            self.push_name_char(c.unwrap_or(core::char::REPLACEMENT_CHARACTER));
+        }
+
+        self.lfn_index = lfn_index;
+        self.long_name = true;
+    }
+
+    pub fn data(&self) -> &[u8; 32] {
+        &self.data
+    }
+
+    /// The long name, or the 8.3 name when the entry has none.
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+
+    pub fn has_long_name(&self) -> bool {
+        self.long_name
+    }
+
+    /// Base of the 8.3 name in the OEM code page, without padding.
+    pub fn short_base(&self) -> &[u8] {
+        trim_spaces(&self.short_name[..8])
+    }
+
+    /// Extension of the 8.3 name in the OEM code page, without padding.
+    pub fn short_ext(&self) -> &[u8] {
+        trim_spaces(&self.short_name[8..])
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        Attributes::from_bits(self.data[11])
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes().contains(Attributes::DIRECTORY)
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            attributes: self.attributes(),
+            size: self.size(),
+            created: self.created(),
+            modified: self.modified(),
+            accessed: self.accessed(),
+        }
+    }
+
+    /// `.` or `..`, present in every directory but the root.
+    pub fn is_dot(&self) -> bool {
+        matches!(self.short_base(), b"." | b"..")
+    }
+
+    pub fn is_volume_id(&self) -> bool {
+        self.attributes().contains(Attributes::VOLUME_ID) && !self.is_dir()
+    }
+
+    pub fn created(&self) -> Option<DateTime> {
+        DateTime::decode(u16_from_bytes(&self.data[16..]), u16_from_bytes(&self.data[14..]), self.data[13])
+    }
+
+    pub fn modified(&self) -> Option<DateTime> {
+        DateTime::decode(u16_from_bytes(&self.data[24..]), u16_from_bytes(&self.data[22..]), 0)
+    }
+
+    /// Only the date of the last access is stored.
+    pub fn accessed(&self) -> Option<DateTime> {
+        DateTime::decode(u16_from_bytes(&self.data[18..]), 0, 0)
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        (u32::from(u16_from_bytes(&self.data[20..])) << 16) | u32::from(u16_from_bytes(&self.data[26..]))
+    }
+
+    pub fn size(&self) -> u32 {
+        u32_from_bytes(&self.data[28..])
+    }
+
+    pub fn dir_cluster(&self) -> u32 {
+        self.dir_cluster
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Slot index of the first long name entry, the short entry index when
+    /// the entry has no long name.
+    pub fn lfn_index(&self) -> u32 {
+        self.lfn_index
+    }
+
+    /// Case insensitive match against the long name, and against the 8.3
+    /// name, `NAME.EXT` or `NAME`.
+    pub fn compare(&self, name: &str) -> bool {
+        if self.long_name && self.name().eq_ignore_ascii_case(name) {
+            return true;
+        }
+
+        let short_name = self.short_name;
+        let base = trim_spaces(&short_name[..8]);
+        let ext = trim_spaces(&short_name[8..]);
+        let name = name.as_bytes();
+
+        let (name_base, name_ext) = match name.iter().rposition(|&c| c == b'.') {
+            // dot entries have no extension
+            Some(dot) if dot > 0 && name.iter().any(|&c| c != b'.') => (&name[..dot], &name[(dot + 1)..]),
+            _ => (name, &name[..0]),
+        };
+
+        base.eq_ignore_ascii_case(name_base) && ext.eq_ignore_ascii_case(name_ext)
+    }
+}
+
+fn short_name_char(c: u8, lower: bool) -> char {
+    if lower {
+        c.to_ascii_lowercase() as char
+    } else {
+        c as char
     }
 }
 
-impl <'stream, 'bd: 'stream> Iterator for DirIterator<'stream, 'bd> {
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().rposition(|&c| c != b' ').map_or(0, |last| last + 1);
+    &bytes[..len]
+}
+
+impl <'stream, 'bd: 'stream, const N: usize> Iterator for DirIterator<'stream, 'bd, N> {
     type Item = DirEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut data = [0u8; 32];
 
         loop {
+            let index = self.index;
+
             match self.stream.read(&mut data[..32]) {
                 Ok(count) => {
+                    self.index += 1;
+
                     if count != data.len() {
                         // we are at the end of folder
                         return None;
@@ -52,22 +655,201 @@ impl <'stream, 'bd: 'stream> Iterator for DirIterator<'stream, 'bd> {
 
                     if data[0] == 0xe5 {
                         // this is deleted entry
+                        self.lfn.reset();
                         continue;
                     }
 
-                    if  (data[11] & ATTR_LONG_FILE_NAME) == ATTR_LONG_FILE_NAME {
+                    if is_long_name_entry(&data) {
+                        self.lfn.push(index, &data);
                         continue;
                     }
 
-                    return Some(DirEntry::new(data));
-                    /*
-                    if entry.compare(name) {
-                        return Ok(entry);
+                    if data[11] & Attributes::VOLUME_ID.bits() != 0 {
+                        // the volume label, not a file, see Fs::label()
+                        self.lfn.reset();
+                        continue;
+                    }
+
+                    let mut entry = DirEntry::new(data);
+                    entry.dir_cluster = self.stream.first_cluster();
+                    entry.index = index;
+                    entry.lfn_index = index;
+
+                    if let Some((chars, lfn_index)) = self.lfn.take(&data[..11]) {
+                        entry.set_long_name(chars, lfn_index);
                     }
-                    */
+
+                    return Some(entry);
                 },
-                Err(e) => return None,
+                Err(_) => return None,
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fs::Fs;
+    use crate::fs::ram::RamDisk;
+
+    fn short_entry(name: &[u8; 11]) -> [u8; 32] {
+        let mut data = [0u8; 32];
+        data[..11].copy_from_slice(name);
+        data[11] = Attributes::ARCHIVE.bits();
+        data
+    }
+
+    /// Long name entries of `name` as stored, last part first.
+    fn long_name_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+        let count = lfn_entries_count(name).unwrap();
+        (1..=count).rev().map(|ordinal| lfn_entry(name, ordinal, count, lfn_checksum(short_name))).collect()
+    }
+
+    fn write_root(fs: &Fs, entries: &[[u8; 32]]) {
+        for (index, data) in entries.iter().enumerate() {
+            fs.dir_entry_write(0, index as u32, data).unwrap();
+        }
+    }
+
+    /// Names, long name indexes and indexes the root directory lists after
+    /// `entries` are written to it.
+    fn list_root(entries: &[[u8; 32]]) -> Vec<(String, u32, u32)> {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_root(&fs, entries);
+
+        let names = fs.open_dir("/").unwrap().map(|entry| (entry.name().to_string(), entry.lfn_index(), entry.index())).collect();
+        names
+    }
+
+    #[test]
+    fn reads_long_names() {
+        let mut entries = long_name_entries("a name in two entries.txt", b"ALONGN~1TXT");
+        entries.push(short_entry(b"ALONGN~1TXT"));
+        entries.push(short_entry(b"README  TXT"));
+        entries.extend(long_name_entries("Grüße 🦀", b"GR__E~1    "));
+        entries.push(short_entry(b"GR__E~1    "));
+
+        assert_eq!(list_root(&entries), [
+            ("a name in two entries.txt".to_string(), 0, 2),
+            ("README.TXT".to_string(), 3, 3),
+            ("Grüße 🦀".to_string(), 4, 5),
+        ]);
+    }
+
+    #[test]
+    fn reads_lower_case_short_names() {
+        let mut lower_base = short_entry(b"NOTES   TXT");
+        lower_base[12] = NT_LOWER_BASE;
+        let mut lower_both = short_entry(b"MAKEFILEMK ");
+        lower_both[12] = NT_LOWER_BASE | NT_LOWER_EXT;
+
+        let names: Vec<_> = list_root(&[lower_base, lower_both]).into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["notes.TXT", "makefile.mk"]);
+    }
+
+    #[test]
+    fn ignores_broken_long_names() {
+        let mut entries = Vec::new();
+
+        // the short entry was renamed by a tool unaware of long names
+        entries.extend(long_name_entries("checksum mismatch.txt", b"CHECKS~1TXT"));
+        entries.push(short_entry(b"OTHER   TXT"));
+
+        // the first part is missing
+        entries.extend(long_name_entries("one more than 13", b"ONEMOR~1   ").into_iter().take(1));
+        entries.push(short_entry(b"ONEMOR~1   "));
+
+        // a deleted entry splits the run
+        let mut run = long_name_entries("one more than 13", b"ONEMOR~2   ");
+        run.insert(1, [0xE5; 32]);
+        entries.extend(run);
+        entries.push(short_entry(b"ONEMOR~2   "));
+
+        let names: Vec<_> = list_root(&entries).into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["OTHER.TXT", "ONEMOR~1", "ONEMOR~2"]);
+    }
+
+    #[test]
+    fn compares_both_names() {
+        let mut entries = long_name_entries("Long Name.txt", b"LONGNA~1TXT");
+        entries.push(short_entry(b"LONGNA~1TXT"));
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_root(&fs, &entries);
+
+        let entry = fs.open_dir("/").unwrap().next().unwrap();
+        assert!(entry.compare("long name.TXT"));
+        assert!(entry.compare("longna~1.txt"));
+        assert!(!entry.compare("Long Name"));
+    }
+
+    #[test]
+    fn short_name_basis() {
+        let name = ShortName::new("README.TXT").unwrap();
+        assert_eq!(name.basis(), b"README  TXT");
+        assert!(name.is_exact() && !name.is_lossy());
+
+        let name = ShortName::new("readme.txt").unwrap();
+        assert_eq!(name.basis(), b"README  TXT");
+        assert!(!name.is_exact() && !name.is_lossy());
+
+        let name = ShortName::new("long file name.html").unwrap();
+        assert_eq!(name.basis(), b"LONGFILEHTM");
+        assert!(name.is_lossy());
+
+        let name = ShortName::new(".profile").unwrap();
+        assert_eq!(name.basis(), b"PROFILE    ");
+        assert!(name.is_lossy());
+
+        let name = ShortName::new("a+b.c").unwrap();
+        assert_eq!(name.basis(), b"A_B     C  ");
+        assert!(name.is_lossy());
+
+        assert_eq!(ShortName::new("...").err(), Some(FsErr::InvalidName));
+    }
+
+    #[test]
+    fn short_name_tail() {
+        let name = ShortName::new("long file name.html").unwrap();
+        assert_eq!(&name.with_tail(1), b"LONGFI~1HTM");
+        assert_eq!(&name.with_tail(42), b"LONGF~42HTM");
+        assert_eq!(&name.with_tail(999_999), b"L~999999HTM");
+
+        let name = ShortName::new("ab c").unwrap();
+        assert_eq!(&name.with_tail(7), b"ABC~7      ");
+
+        assert_eq!(name.tail_of(b"ABC~7      "), Some(7));
+        assert_eq!(name.tail_of(b"ABC~07     "), None);
+        assert_eq!(name.tail_of(b"ABD~7      "), None);
+        assert_eq!(name.tail_of(b"ABC        "), None);
+    }
+
+    #[test]
+    fn long_name_entries_round_trip() {
+        let names = ["a", "exactly 13 ch", "one more than 13", "Grüße, 日本語 and 🦀.txt", &"x".repeat(LONG_NAME_MAX)];
+
+        for name in names.iter() {
+            let mut builder = LongNameBuilder::new();
+
+            for (index, data) in long_name_entries(name, b"LONGNA~1TXT").iter().enumerate() {
+                builder.push(index as u32, data);
+            }
+
+            let (chars, first_index) = builder.take(b"LONGNA~1TXT").unwrap();
+            let decoded: String = core::char::decode_utf16(chars.iter().copied()).map(|c| c.unwrap()).collect();
+            assert_eq!(&decoded, name);
+            assert_eq!(first_index, 0);
+        }
+    }
+
+    #[test]
+    fn long_name_entries_count() {
+        assert_eq!(lfn_entries_count("exactly 13 ch"), Ok(1));
+        assert_eq!(lfn_entries_count("one more than 13"), Ok(2));
+        assert_eq!(lfn_entries_count("🦀🦀🦀🦀🦀🦀🦀"), Ok(2));
+        assert_eq!(lfn_entries_count(""), Err(FsErr::InvalidName));
+        assert_eq!(lfn_entries_count(&"x".repeat(LONG_NAME_MAX + 1)), Err(FsErr::InvalidName));
+    }
+}
@@ -1,22 +1,136 @@
 
-use super::stream::{Stream, SeekFrom};
-use super::sector::FsErr;
+use super::stream::{Stream, SeekFrom, seek_position};
+use super::sector::{FsErr, CACHE_BLOCKS};
+use super::fs::Fs;
 use crate::fs::stream::{Seek, Read, Write};
 
-struct File<'stream, 'bd: 'stream> {
-    stream: Stream<'bd, 'stream>,
+/// How to open a file, the same options as `std::fs::OpenOptions`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OpenOptions {
+    pub(crate) read: bool,
+    pub(crate) write: bool,
+    pub(crate) append: bool,
+    pub(crate) truncate: bool,
+    pub(crate) create: bool,
+    pub(crate) create_new: bool,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Every write goes to the end of the file, implies `write`.
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Fails with `AlreadyExists` if the file exists, `create` and
+    /// `truncate` are then ignored.
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn open<'stream, 'bd, const N: usize>(&self, fs: &'stream Fs<'bd, N>, path: &str) -> Result<File<'stream, 'bd, N>, FsErr> {
+        fs.open_with_options(path, self)
+    }
+
+    pub(crate) fn is_writable(&self) -> bool {
+        self.write || self.append
+    }
+
+    /// Rejects the combinations std rejects too.
+    pub(crate) fn validate(&self) -> Result<(), FsErr> {
+        if !self.read && !self.is_writable() {
+            return Err(FsErr::BadOpenOptions);
+        }
+
+        if (self.truncate || self.create || self.create_new) && !self.is_writable() {
+            return Err(FsErr::BadOpenOptions);
+        }
+
+        if self.truncate && self.append && !self.create_new {
+            return Err(FsErr::BadOpenOptions);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct File<'stream, 'bd: 'stream, const N: usize = CACHE_BLOCKS> {
+    stream: Stream<'stream, 'bd, N>,
     size: u32,
+    // may be past the end of the file, the stream then stays at the end
+    pos: u32,
+    // directory entry of the file, first cluster of its directory and slot
+    dir_cluster: u32,
+    index: u32,
+    accessed: bool,
+    modified: bool,
+
+    readable: bool,
+    writable: bool,
+    append: bool,
 }
 
-impl <'stream, 'bd: 'stream> File <'stream, 'bd> {
+impl <'stream, 'bd: 'stream, const N: usize> File <'stream, 'bd, N> {
+    pub(crate) fn new(stream: Stream<'stream, 'bd, N>, size: u32, dir_cluster: u32, index: u32) -> Self {
+        Self {
+            stream,
+            size,
+            pos: 0,
+            dir_cluster,
+            index,
+            accessed: false,
+            modified: false,
+            readable: true,
+            writable: true,
+            append: false,
+        }
+    }
+
+    pub(crate) fn set_mode(&mut self, options: &OpenOptions) {
+        self.readable = options.read;
+        self.writable = options.is_writable();
+        self.append = options.append;
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
     pub fn read(&mut self, buff: &mut[u8]) -> Result<usize, FsErr> {
-        let pos = self.stream.seek(SeekFrom::Current(0))?;
+        if !self.readable {
+            return Err(FsErr::AccessDenied);
+        }
 
-        if pos == self.size {
+        if self.pos >= self.size {
             return Err(FsErr::EndOfFile);
         }
 
-        let bytes_to_read = core::cmp::min(buff.len(), (self.size - pos) as usize);
+        let bytes_to_read = core::cmp::min(buff.len(), (self.size - self.pos) as usize);
         let mut bytes_read = 0;
 
         while bytes_read < bytes_to_read {
@@ -31,11 +145,36 @@ impl <'stream, 'bd: 'stream> File <'stream, 'bd> {
                 }
             }
         }
-        
+
+        self.pos = self.stream.position();
+
+        // the access date is stamped once per open file
+        if !self.accessed {
+            self.accessed = true;
+            self.stream.fs().dir_entry_accessed(self.dir_cluster, self.index)?;
+        }
+
         Ok(bytes_read)
     }
 
     pub fn write(&mut self, buff: &[u8]) -> Result<usize, FsErr> {
+        if !self.writable {
+            return Err(FsErr::AccessDenied);
+        }
+
+        if self.append {
+            self.seek(SeekFrom::End(0))?;
+        }
+
+        // a seek past the end leaves a gap that reads back as zeros
+        let zero = [0u8; 512];
+        while self.stream.position() < self.pos {
+            let len = core::cmp::min(zero.len(), (self.pos - self.stream.position()) as usize);
+            self.stream.write(&zero[..len])?;
+            self.size = core::cmp::max(self.size, self.stream.position());
+            self.modified = true;
+        }
+
         let mut bytes_written = 0;
 
         while bytes_written != buff.len() {
@@ -51,20 +190,252 @@ impl <'stream, 'bd: 'stream> File <'stream, 'bd> {
             }
         }
 
+        self.pos = self.stream.position();
+        self.size = core::cmp::max(self.size, self.pos);
+        self.modified = true;
         Ok(bytes_written)
     }
 
-    pub fn seek(&mut self, offset: SeekFrom) -> Result<(), FsErr> {
-        self.stream.seek(offset)?;
-        // need to check file border
-        Ok(())
+    /// Seeks past the end are allowed, the next write fills the gap with
+    /// zeros. Returns the new position.
+    pub fn seek(&mut self, offset: SeekFrom) -> Result<u32, FsErr> {
+        let pos = match offset {
+            SeekFrom::Start(start) => start,
+            SeekFrom::Current(current) => seek_position(self.pos, current)?,
+            SeekFrom::End(end) => seek_position(self.size, end)?,
+        };
+
+        self.stream.seek(SeekFrom::Start(core::cmp::min(pos, self.size)))?;
+        self.pos = pos;
+        Ok(pos)
     }
     
+    /// Truncates the file or grows it with zeros.
     pub fn resize(&mut self, size: u32) -> Result<(), FsErr> {
-        todo!();
+        if !self.writable {
+            return Err(FsErr::AccessDenied);
+        }
+
+        let old_size = self.size;
+        let cluster_size = self.stream.fs().cluster_size;
+
+        self.stream.set_len(size.div_ceil(cluster_size))?;
+        self.size = size;
+
+        if size > old_size {
+            let zero = [0u8; 512];
+            self.stream.seek(SeekFrom::Start(old_size))?;
+
+            let mut left = (size - old_size) as usize;
+            while left != 0 {
+                let len = self.stream.write(&zero[..core::cmp::min(left, zero.len())])?;
+                left -= len;
+            }
+        }
+
+        let fs = self.stream.fs();
+        fs.dir_entry_modified(self.dir_cluster, self.index, self.stream.first_cluster(), size)?;
+
+        self.stream.seek(SeekFrom::Start(core::cmp::min(self.pos, size)))?;
+        Ok(())
     }
     
-    pub fn close(mut self) -> Result<(), FsErr> {
+    /// Writes the size, first cluster and modification time back to the
+    /// directory entry, then flushes the file system.
+    pub fn flush(&mut self) -> Result<(), FsErr> {
+        if self.modified {
+            let fs = self.stream.fs();
+            fs.dir_entry_modified(self.dir_cluster, self.index, self.stream.first_cluster(), self.size)?;
+            self.modified = false;
+        }
+
         self.stream.flush()
     }
-}
\ No newline at end of file
+
+    pub fn close(mut self) -> Result<(), FsErr> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::dir::Attributes;
+    use crate::fs::ram::RamDisk;
+
+    /// Everything from the start of the file.
+    fn read_all(file: &mut File) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buff = [0u8; 700];
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        loop {
+            match file.read(&mut buff) {
+                Ok(len) => data.extend_from_slice(&buff[..len]),
+                Err(FsErr::EndOfFile) => return data,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn resize() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        let free = fs.free_clusters_count().unwrap();
+        let cluster_size = fs.cluster_size;
+        let clusters = |size: u32| size.div_ceil(cluster_size);
+
+        let mut file = fs.create("/A.TXT").unwrap();
+        file.write(&[0xAB; 3000]).unwrap();
+
+        file.resize(5000).unwrap();
+        assert_eq!(file.size(), 5000);
+        assert_eq!(fs.free_clusters_count().unwrap(), free - clusters(5000));
+        let data = read_all(&mut file);
+        assert_eq!(data[..3000], [0xAB; 3000]);
+        assert_eq!(data[3000..], [0; 2000]);
+
+        file.resize(100).unwrap();
+        assert_eq!(fs.free_clusters_count().unwrap(), free - clusters(100));
+        assert_eq!(read_all(&mut file), [0xAB; 100]);
+
+        // what was cut off does not come back
+        file.resize(3000).unwrap();
+        let data = read_all(&mut file);
+        assert_eq!(data[..100], [0xAB; 100]);
+        assert_eq!(data[100..], [0; 2900]);
+        file.close().unwrap();
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&fs, "/A.TXT").unwrap();
+        assert_eq!(file.size(), 3000);
+        file.resize(0).unwrap();
+        file.close().unwrap();
+        assert_eq!(fs.free_clusters_count().unwrap(), free);
+        assert_eq!(fs.open("/A.TXT").unwrap().size(), 0);
+
+        let mut file = OpenOptions::new().read(true).open(&fs, "/A.TXT").unwrap();
+        assert_eq!(file.resize(10), Err(FsErr::AccessDenied));
+    }
+
+    #[test]
+    fn seek() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        let cluster_size = fs.cluster_size;
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&fs, "/A.TXT").unwrap();
+        file.write(b"0123456789").unwrap();
+
+        let mut buff = [0u8; 3];
+        assert_eq!(file.seek(SeekFrom::End(-3)), Ok(7));
+        assert_eq!(file.read(&mut buff), Ok(3));
+        assert_eq!(&buff, b"789");
+        assert_eq!(file.seek(SeekFrom::Current(-6)), Ok(4));
+        assert_eq!(file.read(&mut buff), Ok(3));
+        assert_eq!(&buff, b"456");
+
+        assert_eq!(file.seek(SeekFrom::Current(-8)), Err(FsErr::NegativeSeek));
+        assert_eq!(file.seek(SeekFrom::End(-11)), Err(FsErr::NegativeSeek));
+
+        // past the end reads nothing and writing fills the gap
+        assert_eq!(file.seek(SeekFrom::End(5)), Ok(15));
+        assert_eq!(file.read(&mut buff), Err(FsErr::EndOfFile));
+        assert_eq!(file.size(), 10);
+        file.write(b"ab").unwrap();
+        assert_eq!(file.size(), 17);
+        assert_eq!(read_all(&mut file), b"0123456789\0\0\0\0\0ab");
+
+        // on a cluster boundary, into a cluster not allocated yet
+        let end = 2 * cluster_size;
+        assert_eq!(file.seek(SeekFrom::Start(end)), Ok(end));
+        file.write(b"c").unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(0)), Ok(end + 1));
+        file.close().unwrap();
+
+        let mut file = fs.open("/A.TXT").unwrap();
+        let data = read_all(&mut file);
+        assert_eq!(data.len() as u32, end + 1);
+        assert!(data[17..(end as usize)].iter().all(|&b| b == 0));
+        assert_eq!(data[end as usize], b'c');
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        fs.create("/A.TXT").unwrap().close().unwrap();
+
+        let bad = [
+            OpenOptions::new(),
+            *OpenOptions::new().read(true).truncate(true),
+            *OpenOptions::new().read(true).create(true),
+            *OpenOptions::new().read(true).create_new(true),
+            *OpenOptions::new().append(true).truncate(true),
+        ];
+
+        for options in bad.iter() {
+            assert_eq!(options.open(&fs, "/A.TXT").err(), Some(FsErr::BadOpenOptions));
+        }
+
+        assert_eq!(OpenOptions::new().write(true).open(&fs, "/B.TXT").err(), Some(FsErr::FileOrFolderDesntExist));
+        assert_eq!(OpenOptions::new().read(true).open(&fs, "/").err(), Some(FsErr::DirEntryNotFile));
+    }
+
+    #[test]
+    fn open_options() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&fs, "/A.TXT").unwrap();
+        file.write(b"hello").unwrap();
+        assert_eq!(file.read(&mut [0u8; 5]), Err(FsErr::AccessDenied));
+        file.close().unwrap();
+        assert_eq!(OpenOptions::new().write(true).create_new(true).open(&fs, "/a.txt").err(), Some(FsErr::AlreadyExists));
+
+        // create opens the existing file as it is
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&fs, "/A.TXT").unwrap();
+        assert_eq!(read_all(&mut file), b"hello");
+        file.close().unwrap();
+
+        let mut file = OpenOptions::new().read(true).append(true).open(&fs, "/A.TXT").unwrap();
+        file.seek(SeekFrom::Start(1)).unwrap();
+        file.write(b" world").unwrap();
+        assert_eq!(read_all(&mut file), b"hello world");
+        file.close().unwrap();
+
+        let mut file = OpenOptions::new().read(true).write(true).truncate(true).open(&fs, "/A.TXT").unwrap();
+        assert_eq!(file.size(), 0);
+        file.write(b"new").unwrap();
+        file.close().unwrap();
+
+        let mut file = fs.open("/A.TXT").unwrap();
+        assert_eq!(read_all(&mut file), b"new");
+        assert_eq!(file.write(b"x"), Err(FsErr::AccessDenied));
+    }
+
+    #[test]
+    fn read_only_attribute() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        let mut file = fs.create("/A.TXT").unwrap();
+        file.write(b"kept").unwrap();
+        file.close().unwrap();
+        fs.set_attributes("/A.TXT", Attributes::READ_ONLY).unwrap();
+
+        let writes = [
+            *OpenOptions::new().write(true),
+            *OpenOptions::new().append(true),
+            *OpenOptions::new().write(true).truncate(true),
+            *OpenOptions::new().write(true).create(true),
+        ];
+
+        for options in writes.iter() {
+            assert_eq!(options.open(&fs, "/A.TXT").err(), Some(FsErr::AccessDenied));
+        }
+
+        assert_eq!(read_all(&mut fs.open("/A.TXT").unwrap()), b"kept");
+
+        fs.set_attributes("/A.TXT", Attributes::ARCHIVE).unwrap();
+        OpenOptions::new().write(true).open(&fs, "/A.TXT").unwrap().close().unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+use super::sector::{BlockDeviceIo, FsErr, BLOCK_MIN_SIZE, BLOCK_MAX_SIZE};
+use super::fs::{FatType, u16_to_bytes, u32_to_bytes,
+    FS_INFO_LEAD_SIGNATURE, FS_INFO_STRUCT_SIGNATURE, FS_INFO_TRAIL_SIGNATURE};
+
+const MEDIA_FIXED_DISK: u8 = 0xF8;
+const ROOT_ENTRY_COUNT: u32 = 512;
+const FAT32_RESERVED_SECTORS: u32 = 32;
+const FAT32_ROOT_CLUSTER: u32 = 2;
+const FAT32_FS_INFO_SECTOR: u32 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u32 = 6;
+const ATTR_VOLUME_ID: u8 = 0x08;
+
+pub struct FormatOptions {
+    /// Picked from the volume size when not set.
+    pub fat_type: Option<FatType>,
+    /// Cluster size in bytes, picked from the volume size when not set.
+    pub cluster_size: Option<u32>,
+    pub tables_count: u32,
+    /// Space padded, also written as the volume-ID entry of the root
+    /// directory unless it is `NO NAME`.
+    pub volume_label: [u8; 11],
+    pub volume_id: u32,
+    pub oem_name: [u8; 8],
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            fat_type: None,
+            cluster_size: None,
+            tables_count: 2,
+            volume_label: *b"NO NAME    ",
+            volume_id: 0,
+            oem_name: *b"MSWIN4.1",
+        }
+    }
+}
+
+struct Layout {
+    fat_type: FatType,
+    sector_size: u32,
+    sectors_in_cluster: u32,
+    reserved_sectors: u32,
+    root_entry_count: u32,
+    table_size: u32,
+    total_sectors: u32,
+    clusters_count: u32,
+}
+
+/// Default FAT type by volume size, FAT32 below 260MB and FAT16 below 4.1MB
+/// are avoided as the Microsoft specification recommends.
+fn default_fat_type(volume_size: u64) -> FatType {
+    if volume_size >= 532_480 * 512 {
+        FatType::Fat32
+    } else if volume_size >= 8_400 * 512 {
+        FatType::Fat16
+    } else {
+        FatType::Fat12
+    }
+}
+
+/// Cluster sizes from the Microsoft specification tables.
+fn default_cluster_size(fat_type: FatType, volume_size: u64) -> u32 {
+    let sectors = volume_size / 512;
+
+    let sectors_in_cluster = match fat_type {
+        FatType::Fat12 => {
+            // smallest cluster keeping the count below the FAT16 threshold
+            let mut sectors_in_cluster = 1;
+
+            while sectors / sectors_in_cluster >= 4_000 && sectors_in_cluster < 128 {
+                sectors_in_cluster *= 2;
+            }
+
+            sectors_in_cluster
+        },
+        FatType::Fat16 => match sectors {
+            0..=32_680 => 2,
+            32_681..=262_144 => 4,
+            262_145..=524_288 => 8,
+            524_289..=1_048_576 => 16,
+            1_048_577..=2_097_152 => 32,
+            _ => 64,
+        },
+        FatType::Fat32 => match sectors {
+            0..=532_480 => 1,
+            532_481..=16_777_216 => 8,
+            16_777_217..=33_554_432 => 16,
+            33_554_433..=67_108_864 => 32,
+            _ => 64,
+        },
+    };
+
+    sectors_in_cluster as u32 * 512
+}
+
+fn layout(io: &dyn BlockDeviceIo, options: &FormatOptions) -> Result<Layout, FsErr> {
+    let sector_size = io.block_size();
+
+    if !sector_size.is_power_of_two() || !(BLOCK_MIN_SIZE..=BLOCK_MAX_SIZE).contains(&(sector_size as usize)) {
+        return Err(FsErr::BadBlockSize);
+    }
+
+    let total_sectors = io.block_count();
+    let volume_size = u64::from(total_sectors) * u64::from(sector_size);
+    let fat_type = options.fat_type.unwrap_or_else(|| default_fat_type(volume_size));
+    let cluster_size = options.cluster_size.unwrap_or_else(|| {
+        core::cmp::max(default_cluster_size(fat_type, volume_size), sector_size)
+    });
+
+    if !cluster_size.is_power_of_two() || cluster_size < sector_size || cluster_size / sector_size > 128 {
+        return Err(FsErr::BadFormatOptions);
+    }
+
+    if options.tables_count == 0 || options.tables_count > 16 {
+        return Err(FsErr::BadFormatOptions);
+    }
+
+    let (reserved_sectors, root_entry_count) = match fat_type {
+        FatType::Fat32 => (FAT32_RESERVED_SECTORS, 0),
+        FatType::Fat16 | FatType::Fat12 => (1, ROOT_ENTRY_COUNT),
+    };
+
+    let sectors_in_cluster = cluster_size / sector_size;
+    let root_dir_sectors = (root_entry_count * 32).div_ceil(sector_size);
+
+    // the FATs take space from the data area, grow them until they fit
+    let mut table_size = 1;
+    let clusters_count = loop {
+        let meta_sectors = reserved_sectors + options.tables_count * table_size + root_dir_sectors;
+
+        if meta_sectors >= total_sectors {
+            return Err(FsErr::BadFormatOptions);
+        }
+
+        let clusters_count = (total_sectors - meta_sectors) / sectors_in_cluster;
+        let needed = clusters_count.checked_add(2)
+            .and_then(|count| fat_type.table_bytes(count))
+            .ok_or(FsErr::BadFormatOptions)?
+            .div_ceil(sector_size);
+
+        if needed <= table_size {
+            break clusters_count;
+        }
+
+        table_size = needed;
+    };
+
+    if FatType::from_clusters_count(clusters_count) != fat_type {
+        return Err(FsErr::BadFormatOptions);
+    }
+
+    Ok(Layout {
+        fat_type,
+        sector_size,
+        sectors_in_cluster,
+        reserved_sectors,
+        root_entry_count,
+        table_size,
+        total_sectors,
+        clusters_count,
+    })
+}
+
+fn write_boot_sector(io: &dyn BlockDeviceIo, layout: &Layout, options: &FormatOptions) -> Result<(), FsErr> {
+    let mut bs = [0u8; BLOCK_MAX_SIZE];
+
+    bs[0] = 0xEB;
+    bs[1] = if layout.fat_type == FatType::Fat32 { 0x58 } else { 0x3C };
+    bs[2] = 0x90;
+    bs[3..11].copy_from_slice(&options.oem_name);
+    u16_to_bytes(&mut bs[11..], layout.sector_size as u16);
+    bs[13] = layout.sectors_in_cluster as u8;
+    u16_to_bytes(&mut bs[14..], layout.reserved_sectors as u16);
+    bs[16] = options.tables_count as u8;
+    u16_to_bytes(&mut bs[17..], layout.root_entry_count as u16);
+
+    if layout.total_sectors < 0x10000 && layout.fat_type != FatType::Fat32 {
+        u16_to_bytes(&mut bs[19..], layout.total_sectors as u16);
+    } else {
+        u32_to_bytes(&mut bs[32..], layout.total_sectors);
+    }
+
+    bs[21] = MEDIA_FIXED_DISK;
+    // geometry is informational only
+    u16_to_bytes(&mut bs[24..], 63);
+    u16_to_bytes(&mut bs[26..], 255);
+
+    let ext = match layout.fat_type {
+        FatType::Fat32 => {
+            u32_to_bytes(&mut bs[36..], layout.table_size);
+            u32_to_bytes(&mut bs[44..], FAT32_ROOT_CLUSTER);
+            u16_to_bytes(&mut bs[48..], FAT32_FS_INFO_SECTOR as u16);
+            u16_to_bytes(&mut bs[50..], FAT32_BACKUP_BOOT_SECTOR as u16);
+            bs[82..90].copy_from_slice(b"FAT32   ");
+            64
+        },
+        FatType::Fat16 | FatType::Fat12 => {
+            u16_to_bytes(&mut bs[22..], layout.table_size as u16);
+            bs[54..62].copy_from_slice(if layout.fat_type == FatType::Fat12 { b"FAT12   " } else { b"FAT16   " });
+            36
+        },
+    };
+
+    bs[ext] = 0x80;
+    bs[ext + 2] = 0x29;
+    u32_to_bytes(&mut bs[(ext + 3)..], options.volume_id);
+    bs[(ext + 7)..(ext + 18)].copy_from_slice(&options.volume_label);
+
+    bs[510] = 0x55;
+    bs[511] = 0xAA;
+
+    let sector_size = layout.sector_size as usize;
+    io.write(0, &bs[..sector_size])?;
+
+    if layout.fat_type == FatType::Fat32 {
+        io.write(FAT32_BACKUP_BOOT_SECTOR, &bs[..sector_size])?;
+
+        let mut fs_info = [0u8; BLOCK_MAX_SIZE];
+        u32_to_bytes(&mut fs_info[0..], FS_INFO_LEAD_SIGNATURE);
+        u32_to_bytes(&mut fs_info[484..], FS_INFO_STRUCT_SIGNATURE);
+        // the root directory takes the first cluster
+        u32_to_bytes(&mut fs_info[488..], layout.clusters_count - 1);
+        u32_to_bytes(&mut fs_info[492..], FAT32_ROOT_CLUSTER + 1);
+        u32_to_bytes(&mut fs_info[508..], FS_INFO_TRAIL_SIGNATURE);
+
+        io.write(FAT32_FS_INFO_SECTOR, &fs_info[..sector_size])?;
+        io.write(FAT32_BACKUP_BOOT_SECTOR + FAT32_FS_INFO_SECTOR, &fs_info[..sector_size])?;
+    }
+
+    Ok(())
+}
+
+fn write_tables(io: &dyn BlockDeviceIo, layout: &Layout, options: &FormatOptions) -> Result<(), FsErr> {
+    let zero = [0u8; BLOCK_MAX_SIZE];
+    let mut first = [0u8; BLOCK_MAX_SIZE];
+    let sector_size = layout.sector_size as usize;
+
+    // entry 0 carries the media byte, entry 1 is end of chain
+    match layout.fat_type {
+        FatType::Fat32 => {
+            u32_to_bytes(&mut first[0..], 0x0FFF_FF00 | u32::from(MEDIA_FIXED_DISK));
+            u32_to_bytes(&mut first[4..], 0x0FFF_FFFF);
+            // root directory cluster
+            u32_to_bytes(&mut first[8..], 0x0FFF_FFFF);
+        },
+        FatType::Fat16 => {
+            u16_to_bytes(&mut first[0..], 0xFF00 | u16::from(MEDIA_FIXED_DISK));
+            u16_to_bytes(&mut first[2..], 0xFFFF);
+        },
+        FatType::Fat12 => {
+            first[0] = MEDIA_FIXED_DISK;
+            first[1] = 0xFF;
+            first[2] = 0xFF;
+        },
+    }
+
+    for table in 0..options.tables_count {
+        let table_first_sector = layout.reserved_sectors + table * layout.table_size;
+        io.write(table_first_sector, &first[..sector_size])?;
+
+        for sector in 1..layout.table_size {
+            io.write(table_first_sector + sector, &zero[..sector_size])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_root_dir(io: &dyn BlockDeviceIo, layout: &Layout, options: &FormatOptions) -> Result<(), FsErr> {
+    let mut first = [0u8; BLOCK_MAX_SIZE];
+    let zero = [0u8; BLOCK_MAX_SIZE];
+    let sector_size = layout.sector_size as usize;
+
+    if &options.volume_label != b"NO NAME    " {
+        first[..11].copy_from_slice(&options.volume_label);
+        first[11] = ATTR_VOLUME_ID;
+    }
+
+    // the FAT32 root cluster is the first data cluster, right after the FATs
+    // just like the fixed root directory on FAT12/16
+    let root_first_sector = layout.reserved_sectors + options.tables_count * layout.table_size;
+    let root_sectors = match layout.fat_type {
+        FatType::Fat32 => layout.sectors_in_cluster,
+        FatType::Fat16 | FatType::Fat12 => (layout.root_entry_count * 32).div_ceil(layout.sector_size),
+    };
+
+    io.write(root_first_sector, &first[..sector_size])?;
+
+    for sector in 1..root_sectors {
+        io.write(root_first_sector + sector, &zero[..sector_size])?;
+    }
+
+    Ok(())
+}
+
+/// Creates an empty FAT file system over the whole device.
+pub fn format(io: &dyn BlockDeviceIo, options: FormatOptions) -> Result<(), FsErr> {
+    let layout = layout(io, &options)?;
+
+    // the old boot sector is wiped first and the new one written last, so an
+    // interrupted format leaves nothing mountable behind
+    let zero = [0u8; BLOCK_MAX_SIZE];
+    for sector in 0..layout.reserved_sectors {
+        io.write(sector, &zero[..layout.sector_size as usize])?;
+    }
+
+    write_tables(io, &layout, &options)?;
+    write_root_dir(io, &layout, &options)?;
+    write_boot_sector(io, &layout, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fs::Fs;
+    use crate::fs::ram::RamDisk;
+
+    fn round_trip(block_count: u32, fat_type: Option<FatType>, expected: FatType) {
+        let disk = RamDisk::new(block_count);
+        let options = FormatOptions { fat_type, volume_label: *b"TEST VOLUME", volume_id: 0x1234_5678, ..Default::default() };
+        format(&disk, options).unwrap();
+
+        let fs = Fs::mount(&disk).unwrap();
+        assert_eq!(fs.fat_type(), expected);
+        assert_eq!(fs.volume_id(), 0x1234_5678);
+        assert_eq!(fs.label().unwrap(), *b"TEST VOLUME");
+        assert_eq!(fs.open_dir("/").unwrap().count(), 0);
+        let free = fs.free_clusters_count().unwrap();
+
+        let mut file = fs.create("/round trip.txt").unwrap();
+        file.write(b"written before unmount").unwrap();
+        file.close().unwrap();
+        fs.unmount().unwrap();
+
+        let fs = Fs::mount(&disk).unwrap();
+        let mut file = fs.open("/round trip.txt").unwrap();
+        let mut buff = [0u8; 32];
+        let len = file.read(&mut buff).unwrap();
+        assert_eq!(&buff[..len], b"written before unmount");
+        assert_eq!(fs.free_clusters_count().unwrap(), free - 1);
+    }
+
+    #[test]
+    fn fat12_round_trip() {
+        round_trip(2880, None, FatType::Fat12);
+    }
+
+    #[test]
+    fn fat16_round_trip() {
+        round_trip(20000, None, FatType::Fat16);
+    }
+
+    #[test]
+    fn fat32_round_trip() {
+        round_trip(600000, Some(FatType::Fat32), FatType::Fat32);
+    }
+
+    #[test]
+    fn too_small_for_fat_type() {
+        let disk = RamDisk::new(2880);
+        let options = FormatOptions { fat_type: Some(FatType::Fat32), ..Default::default() };
+        assert_eq!(format(&disk, options), Err(FsErr::BadFormatOptions));
+    }
+}
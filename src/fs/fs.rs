@@ -1,18 +1,179 @@
-use super::sector::{BlockDeviceIo, Sector, FsErr};
+use core::cell::Cell;
+use super::sector::{BlockDeviceIo, Sector, FsErr, BLOCK_MIN_SIZE, BLOCK_MAX_SIZE, CACHE_BLOCKS};
+use super::stream::Stream;
+use super::stream::Read;
+use super::dir::{DirIterator, DirEntry, Metadata, ShortName, lfn_checksum, lfn_entries_count, lfn_entry,
+    Attributes, validate_name, short_name_byte, entry_set_accessed, entry_set_modified, entry_set_created, entry_set_first_cluster,
+    entry_set_size, is_long_name_entry};
+use super::time::{DateTime, TimeProvider, AccessTime};
+use super::file::{File, OpenOptions};
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum FatType {
     Fat32,
     Fat16,
     Fat12,
 }
 
-pub struct Fs<'bd> {
-    io: &'bd dyn BlockDeviceIo,
-    pub sector: Sector<'bd>,
+impl FatType {
+    /// The FAT type is determined by the count of data clusters alone, the
+    /// file system type string in the boot sector is informational only.
+    pub fn from_clusters_count(count: u32) -> Self {
+        if count < 4085 {
+            FatType::Fat12
+        } else if count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// Bytes needed to store `count` FAT entries, None when that doesn't
+    /// fit in a u32.
+    pub fn table_bytes(self, count: u32) -> Option<u32> {
+        match self {
+            FatType::Fat32 => count.checked_mul(4),
+            FatType::Fat16 => count.checked_mul(2),
+            FatType::Fat12 => count.checked_add(count.div_ceil(2)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Bpb {
+    pub sector_size: u32,
+    pub sectors_in_cluster: u32,
+    pub reserved_sectors: u32,
+    pub tables_count: u32,
+    pub root_entry_count: u32,
+    pub total_sectors: u32,
+    pub table_size: u32,
+    pub media: u8,
+    // FAT32 only
+    pub ext_flags: u16,
+    pub root_cluster: u32,
+    pub fs_info_sector: u32,
+    pub backup_boot_sector: u32,
+    /// Serial number, 0 when the extended boot record is missing.
+    pub volume_id: u32,
+}
+
+impl Bpb {
+    pub fn parse(bs: &[u8]) -> Result<Self, FsErr> {
+        if bs[510] != 0x55 || bs[511] != 0xAA {
+            return Err(FsErr::BadBootSignature);
+        }
+
+        if !(bs[0] == 0xEB && bs[2] == 0x90) && bs[0] != 0xE9 {
+            return Err(FsErr::BadJumpInstruction);
+        }
+
+        let sector_size = u32::from(u16_from_bytes(&bs[11..]));
+
+        match sector_size {
+            512 | 1024 | 2048 | 4096 => {},
+            _ => return Err(FsErr::BadBlockSize),
+        }
+
+        let table_size_16 = u32::from(u16_from_bytes(&bs[22..]));
+
+        let mut bpb = Self {
+            sector_size,
+            sectors_in_cluster: u32::from(bs[13]),
+            reserved_sectors: u32::from(u16_from_bytes(&bs[14..])),
+            tables_count: u32::from(bs[16]),
+            root_entry_count: u32::from(u16_from_bytes(&bs[17..])),
+            total_sectors: match u16_from_bytes(&bs[19..]) {
+                0 => u32_from_bytes(&bs[32..]),
+                n => u32::from(n),
+            },
+            table_size: table_size_16,
+            media: bs[21],
+            ext_flags: 0,
+            root_cluster: 0,
+            fs_info_sector: 0,
+            backup_boot_sector: 0,
+            volume_id: 0,
+        };
+
+        if table_size_16 == 0 {
+            // only version 0.0 of the FAT32 extension is defined
+            if bpb.root_entry_count != 0 || u16_from_bytes(&bs[42..]) != 0 {
+                return Err(FsErr::BadBpb);
+            }
+
+            bpb.table_size = u32_from_bytes(&bs[36..]);
+            bpb.ext_flags = u16_from_bytes(&bs[40..]);
+            bpb.root_cluster = u32_from_bytes(&bs[44..]);
+            bpb.fs_info_sector = u32::from(u16_from_bytes(&bs[48..]));
+            bpb.backup_boot_sector = u32::from(u16_from_bytes(&bs[50..]));
+
+            if bpb.root_cluster < 2 {
+                return Err(FsErr::BadBpb);
+            }
+        } else if bpb.root_entry_count == 0 {
+            return Err(FsErr::BadBpb);
+        }
+
+        let ext = ext_boot_offset(table_size_16 == 0);
+        if bs[ext + 2] == EXT_BOOT_SIGNATURE || bs[ext + 2] == EXT_BOOT_SIGNATURE_NO_LABEL {
+            bpb.volume_id = u32_from_bytes(&bs[(ext + 3)..]);
+        }
+
+        if !bpb.sectors_in_cluster.is_power_of_two() || bpb.reserved_sectors == 0 ||
+            bpb.tables_count == 0 || bpb.table_size == 0 || bpb.total_sectors == 0 {
+            return Err(FsErr::BadBpb);
+        }
+
+        // checked once here, the accessors below add these up unchecked
+        let meta_sectors = bpb.tables_count.checked_mul(bpb.table_size)
+            .and_then(|sectors| sectors.checked_add(bpb.reserved_sectors))
+            .and_then(|sectors| sectors.checked_add(bpb.root_dir_sectors()))
+            .ok_or(FsErr::BadBpb)?;
+
+        if meta_sectors >= bpb.total_sectors {
+            return Err(FsErr::BadBpb);
+        }
+
+        Ok(bpb)
+    }
+
+    /// FAT12/16 only, the fixed root directory follows the last FAT copy.
+    pub fn root_dir_first_sector(&self) -> u32 {
+        self.reserved_sectors + self.tables_count * self.table_size
+    }
+
+    pub fn root_dir_sectors(&self) -> u32 {
+        (self.root_entry_count * 32).div_ceil(self.sector_size)
+    }
+
+    /// Reserved region, all FAT copies and the fixed root directory.
+    pub fn meta_sectors(&self) -> u32 {
+        self.root_dir_first_sector() + self.root_dir_sectors()
+    }
+
+    pub fn data_clusters_count(&self) -> u32 {
+        (self.total_sectors - self.meta_sectors()) / self.sectors_in_cluster
+    }
+}
+
+pub struct Fs<'bd, const N: usize = CACHE_BLOCKS> {
+    pub sector: Sector<'bd, N>,
+    pub bpb: Bpb,
 
     fat_type: FatType,
     table_clusters_count: u32,
     table_first_sector: u32,
+    data_first_sector: u32,
+
+    // FAT32 FSInfo sector, 0 when missing or invalid
+    fs_info_sector: u32,
+    fs_info_dirty: Cell<bool>,
+    free_count: Cell<u32>,
+    next_free: Cell<u32>,
+
+    time_provider: Option<&'bd dyn TimeProvider>,
+    access_time: AccessTime,
 
     pub sector_size: u32,
     pub cluster_size: u32,
@@ -26,12 +187,241 @@ pub enum ClusterValue {
     Bad,
 }
 
-fn u32_from_bytes(bytes: &[u8]) -> u32 {
+const EXT_FLAGS_ACTIVE_TABLE: u16 = 0x000F;
+const EXT_FLAGS_NO_MIRRORING: u16 = 0x0080;
+
+pub(crate) const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+pub(crate) const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+pub(crate) const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// the extended boot record carries the serial number, and the label when
+// its signature is 0x29
+const EXT_BOOT_SIGNATURE: u8 = 0x29;
+const EXT_BOOT_SIGNATURE_NO_LABEL: u8 = 0x28;
+const NO_LABEL: [u8; 11] = *b"NO NAME    ";
+
+fn ext_boot_offset(fat32: bool) -> usize {
+    if fat32 { 64 } else { 36 }
+}
+
+const DIR_ENTRY_SIZE: u32 = 32;
+const DIR_ENTRY_END: u8 = 0x00;
+const DIR_ENTRY_DELETED: u8 = 0xE5;
+const DIR_ENTRY_E5_ESCAPE: u8 = 0x05;
+// a directory may not grow past 2MB
+const DIR_ENTRIES_MAX: u32 = 65536;
+const SHORT_NAME_TAIL_MAX: u32 = 999_999;
+// numeric tails looked for by each directory scan
+const SHORT_NAME_TAILS_PER_SCAN: u32 = 256;
+
+pub(crate) fn u16_from_bytes(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) | (u16::from(bytes[1]) << 8)
+}
+
+pub(crate) fn u32_from_bytes(bytes: &[u8]) -> u32 {
     u32::from(bytes[0]) | (u32::from(bytes[1]) << 8) |
     (u32::from(bytes[2]) << 16) | (u32::from(bytes[3]) << 24)
 }
 
+pub(crate) fn u16_to_bytes(bytes: &mut [u8], value: u16) {
+    bytes[0] = value as u8;
+    bytes[1] = (value >> 8) as u8;
+}
+
+pub(crate) fn u32_to_bytes(bytes: &mut [u8], value: u32) {
+    bytes[0] = value as u8;
+    bytes[1] = (value >> 8) as u8;
+    bytes[2] = (value >> 16) as u8;
+    bytes[3] = (value >> 24) as u8;
+}
+
 impl <'bd> Fs<'bd> {
+    /// Mounts with a cache of `CACHE_BLOCKS` blocks.
+    pub fn mount(io: &'bd dyn BlockDeviceIo) -> Result<Self, FsErr> {
+        Self::mount_with_cache(io)
+    }
+}
+
+impl <'bd, const N: usize> Fs<'bd, N> {
+    /// Mounts with a cache of `N` blocks, `Fs::<16>::mount_with_cache(io)`.
+    pub fn mount_with_cache(io: &'bd dyn BlockDeviceIo) -> Result<Self, FsErr> {
+        let block_size = io.block_size() as usize;
+
+        if !block_size.is_power_of_two() || !(BLOCK_MIN_SIZE..=BLOCK_MAX_SIZE).contains(&block_size) {
+            return Err(FsErr::BadBlockSize);
+        }
+
+        let sector = Sector::new(io);
+        let mut bs = [0u8; BLOCK_MIN_SIZE];
+        sector.read(0, 0, &mut bs)?;
+
+        let bpb = Bpb::parse(&bs)?;
+
+        if bpb.sector_size as usize != block_size {
+            return Err(FsErr::BadBlockSize);
+        }
+
+        if bpb.total_sectors > io.block_count() {
+            return Err(FsErr::PartitionOutOfStorageSpace);
+        }
+
+        let data_clusters_count = bpb.data_clusters_count();
+        let fat_type = FatType::from_clusters_count(data_clusters_count);
+
+        // FAT32 volumes must carry the FAT32 extended BPB and vice versa
+        if (fat_type == FatType::Fat32) != (bpb.root_cluster != 0) {
+            return Err(FsErr::BadBpb);
+        }
+
+        let table_clusters_count = data_clusters_count.checked_add(2).ok_or(FsErr::BadBpb)?;
+        let table_bytes = fat_type.table_bytes(table_clusters_count).ok_or(FsErr::BadBpb)?;
+
+        if u64::from(table_bytes) > u64::from(bpb.table_size) * u64::from(bpb.sector_size) {
+            return Err(FsErr::BadBpb);
+        }
+
+        if bpb.ext_flags & EXT_FLAGS_NO_MIRRORING != 0 &&
+            u32::from(bpb.ext_flags & EXT_FLAGS_ACTIVE_TABLE) >= bpb.tables_count {
+            return Err(FsErr::BadBpb);
+        }
+
+        let mut fs = Self {
+            sector,
+            bpb,
+            fat_type,
+            table_clusters_count,
+            table_first_sector: bpb.reserved_sectors,
+            data_first_sector: bpb.meta_sectors(),
+            fs_info_sector: 0,
+            fs_info_dirty: Cell::new(false),
+            free_count: Cell::new(FS_INFO_UNKNOWN),
+            next_free: Cell::new(2),
+            time_provider: None,
+            access_time: AccessTime::default(),
+            sector_size: bpb.sector_size,
+            cluster_size: bpb.sector_size * bpb.sectors_in_cluster,
+            sectors_in_cluster: bpb.sectors_in_cluster,
+        };
+
+        if fat_type == FatType::Fat32 {
+            fs.fs_info_read()?;
+        }
+
+        Ok(fs)
+    }
+
+    fn fs_info_read(&mut self) -> Result<(), FsErr> {
+        let sector = self.bpb.fs_info_sector;
+
+        if sector == 0 || sector >= self.bpb.reserved_sectors {
+            return Ok(());
+        }
+
+        let mut buff = [0u8; 4];
+        let mut signatures = [0u32; 3];
+
+        for (signature, &offset) in signatures.iter_mut().zip([0, 484, 508].iter()) {
+            self.sector.read(sector, offset, &mut buff)?;
+            *signature = u32_from_bytes(&buff);
+        }
+
+        if signatures != [FS_INFO_LEAD_SIGNATURE, FS_INFO_STRUCT_SIGNATURE, FS_INFO_TRAIL_SIGNATURE] {
+            return Ok(());
+        }
+
+        self.fs_info_sector = sector;
+
+        // both values are hints only, drop the ones that can't be right
+        self.sector.read(sector, 488, &mut buff)?;
+        let free_count = u32_from_bytes(&buff);
+
+        if free_count <= self.data_clusters_count() {
+            self.free_count.set(free_count);
+        }
+
+        self.sector.read(sector, 492, &mut buff)?;
+        let next_free = u32_from_bytes(&buff);
+
+        if (2..self.table_clusters_count).contains(&next_free) {
+            self.next_free.set(next_free);
+        }
+
+        Ok(())
+    }
+
+    fn fs_info_write(&self) -> Result<(), FsErr> {
+        if self.fs_info_sector == 0 || !self.fs_info_dirty.get() {
+            return Ok(());
+        }
+
+        let mut buff = [0u8; 8];
+        u32_to_bytes(&mut buff[0..], self.free_count.get());
+        u32_to_bytes(&mut buff[4..], self.next_free.get());
+
+        self.sector.write(self.fs_info_sector, 488, &buff)?;
+        self.fs_info_dirty.set(false);
+        Ok(())
+    }
+
+    /// Writes the FSInfo hints and every cached sector back to the device.
+    pub fn flush(&self) -> Result<(), FsErr> {
+        self.fs_info_write()?;
+        self.sector.flush()
+    }
+
+    pub fn unmount(self) -> Result<(), FsErr> {
+        self.flush()
+    }
+
+    /// Count of free data clusters, the FAT is scanned once when the FSInfo
+    /// sector doesn't provide it.
+    pub fn free_clusters_count(&self) -> Result<u32, FsErr> {
+        if self.free_count.get() == FS_INFO_UNKNOWN {
+            let mut count = 0;
+
+            for cluster in 2..self.table_clusters_count {
+                if let ClusterValue::Free = self.table_get(cluster)? {
+                    count += 1;
+                }
+            }
+
+            self.free_count.set(count);
+            self.fs_info_dirty.set(true);
+        }
+
+        Ok(self.free_count.get())
+    }
+
+    /// Clock used to stamp directory entries, without one every stamp is
+    /// the start of the FAT epoch.
+    pub fn set_time_provider(&mut self, time_provider: &'bd dyn TimeProvider) {
+        self.time_provider = Some(time_provider);
+    }
+
+    pub fn set_access_time(&mut self, access_time: AccessTime) {
+        self.access_time = access_time;
+    }
+
+    pub fn now(&self) -> DateTime {
+        self.time_provider.map_or(DateTime::EPOCH, |time_provider| time_provider.now())
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Count of clusters the whole volume would hold, including the
+    /// reserved region, the FATs and the fixed root directory.
+    pub fn total_clusters_count(&self) -> u32 {
+        self.bpb.total_sectors / self.sectors_in_cluster
+    }
+
+    /// Count of clusters in the data area, numbered from 2.
+    pub fn data_clusters_count(&self) -> u32 {
+        self.table_clusters_count - 2
+    }
+
     fn fat32_cluster_to_sector_and_offset(&self, cluster: u32) -> (u32, usize) {
         let sector = self.table_first_sector + cluster / (self.sector_size >> 2);
         let offset = ((cluster * 4) % self.sector_size) as usize;
@@ -50,57 +440,143 @@ impl <'bd> Fs<'bd> {
         (sector, offset)
     }
 
+    /// FAT copies kept in sync, only the active one when FAT32 mirroring is
+    /// disabled in ExtFlags.
+    fn table_copies(&self) -> core::ops::Range<u32> {
+        if self.fat_type == FatType::Fat32 && self.bpb.ext_flags & EXT_FLAGS_NO_MIRRORING != 0 {
+            let active = u32::from(self.bpb.ext_flags & EXT_FLAGS_ACTIVE_TABLE);
+            active..(active + 1)
+        } else {
+            0..self.bpb.tables_count
+        }
+    }
+
+    /// FAT12 entries may straddle two sectors.
+    fn table_copy_read_bytes(&self, sector: u32, offset: usize, buff: &mut [u8]) -> Result<(), FsErr> {
+        let len = core::cmp::min(buff.len(), self.sector_size as usize - offset);
+        self.sector.read(sector, offset, &mut buff[..len])?;
+
+        if len < buff.len() {
+            self.sector.read(sector + 1, 0, &mut buff[len..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads from the first FAT copy that doesn't fail with a read error.
+    fn table_read_bytes(&self, sector: u32, offset: usize, buff: &mut [u8]) -> Result<(), FsErr> {
+        let mut result = Err(FsErr::ReadError);
+
+        for copy in self.table_copies() {
+            result = self.table_copy_read_bytes(sector + copy * self.bpb.table_size, offset, buff);
+
+            if result != Err(FsErr::ReadError) {
+                break;
+            }
+        }
+
+        result
+    }
+
+    fn table_write_bytes(&self, sector: u32, offset: usize, buff: &[u8]) -> Result<(), FsErr> {
+        let len = core::cmp::min(buff.len(), self.sector_size as usize - offset);
+
+        for copy in self.table_copies() {
+            let sector = sector + copy * self.bpb.table_size;
+            self.sector.write(sector, offset, &buff[..len])?;
+
+            if len < buff.len() {
+                self.sector.write(sector + 1, 0, &buff[len..])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A link to a cluster outside of the data area is a corrupt FAT.
+    fn table_next(&self, cluster: u32) -> Result<ClusterValue, FsErr> {
+        if !(2..self.table_clusters_count).contains(&cluster) {
+            return Err(FsErr::FatTableError);
+        }
+
+        Ok(ClusterValue::Next(cluster))
+    }
+
     pub fn table_get(&self, cluster: u32) -> Result<ClusterValue, FsErr> {
         match self.fat_type {
             FatType::Fat32 => {
                 let (sector, offset) = self.fat32_cluster_to_sector_and_offset(cluster);
                 let mut buff = [0u8; 4];
-                self.sector.read(sector, offset, &mut buff)?;
+                self.table_read_bytes(sector, offset, &mut buff)?;
                 let val = u32_from_bytes(&buff[..]) & 0x0FFF_FFFF;
 
                 match val {
                     0 => Ok(ClusterValue::Free),
                     0x0FFF_FFF7 => Ok(ClusterValue::Bad),
-                    0x0FFF_FFF8..=core::u32::MAX => Ok(ClusterValue::Last),
-                    value => Ok(ClusterValue::Next(value)),
+                    0x0FFF_FFF8..=u32::MAX => Ok(ClusterValue::Last),
+                    value => self.table_next(value),
                 }
             },
             FatType::Fat16 => {
                 let (sector, offset) = self.fat16_cluster_to_sector_and_offset(cluster);
                 let mut buff = [0u8; 2];
-                self.sector.read(sector, offset, &mut buff)?;
+                self.table_read_bytes(sector, offset, &mut buff)?;
                 let val = (buff[0] as u32) | ((buff[1] as u32) << 8);
         
                 match val {
                     0 => Ok(ClusterValue::Free),
                     0xFFF7 => Ok(ClusterValue::Bad),
                     0xFFF8..=0xFFFF => Ok(ClusterValue::Last),
-                    value => Ok(ClusterValue::Next(value as u32)),
+                    value => self.table_next(value),
                 }
             },
             FatType::Fat12 => {
                 let (sector, offset) = self.fat12_cluster_to_sector_and_offset(cluster);
                 let mut buff = [0u8; 2];
-                self.sector.read(sector, offset, &mut buff)?;
+                self.table_read_bytes(sector, offset, &mut buff)?;
                 let val = (buff[0] as u32) | ((buff[1] as u32) << 8);
         
                 let val = if cluster & 1 == 0 {
-                    (val & 0x0FFF) as u32
+                    val & 0x0FFF
                 } else {
-                    (val >> 4) as u32
+                    val >> 4
                 };
         
                 match val {
                     0 => Ok(ClusterValue::Free),
                     0xFF7 => Ok(ClusterValue::Bad),
                     0xFF8..=0xFFF => Ok(ClusterValue::Last),
-                    value => Ok(ClusterValue::Next(value)),
+                    value => self.table_next(value),
                 }
             }
         }
     }
 
     pub fn table_set(&self, cluster: u32, value: ClusterValue) -> Result<(), FsErr> {
+        let free_count = self.free_count.get();
+
+        if free_count != FS_INFO_UNKNOWN {
+            let was_free = matches!(self.table_get(cluster)?, ClusterValue::Free);
+            let is_free = matches!(value, ClusterValue::Free);
+
+            if was_free != is_free {
+                let free_count = if is_free { free_count.checked_add(1) } else { free_count.checked_sub(1) };
+
+                // a stale hint that goes out of range is dropped, it is
+                // counted again when asked for
+                let free_count = free_count
+                    .filter(|&count| count <= self.data_clusters_count())
+                    .unwrap_or(FS_INFO_UNKNOWN);
+
+                self.free_count.set(free_count);
+                self.fs_info_dirty.set(true);
+            }
+        }
+
+        self.table_write(cluster, value)
+    }
+
+    fn table_write(&self, cluster: u32, value: ClusterValue) -> Result<(), FsErr> {
         match self.fat_type {
             FatType::Fat32 => {
                 let val = match value {
@@ -112,7 +588,7 @@ impl <'bd> Fs<'bd> {
                 
                 let buff = [val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8];
                 let (sector, offset) = self.fat32_cluster_to_sector_and_offset(cluster);
-                self.sector.write(sector, offset, &buff)
+                self.table_write_bytes(sector, offset, &buff)
             },
             FatType::Fat16 => {
                 let value = match value {
@@ -124,7 +600,7 @@ impl <'bd> Fs<'bd> {
                 
                 let buff = [value as u8, (value >> 8) as u8];
                 let (sector, offset) = self.fat16_cluster_to_sector_and_offset(cluster);
-                self.sector.write(sector, offset, &buff)
+                self.table_write_bytes(sector, offset, &buff)
             },
             FatType::Fat12 => {
                 let value = match value {
@@ -136,26 +612,36 @@ impl <'bd> Fs<'bd> {
 
                 let (sector, offset) = self.fat12_cluster_to_sector_and_offset(cluster);
                 let mut buff = [0u8; 2];
-                self.sector.read(sector, offset, &mut buff)?;
+                self.table_read_bytes(sector, offset, &mut buff)?;
 
+                // even entries own the low 12 bits of the pair, odd ones the high 12
                 if cluster & 1 == 0 {
                     buff[0] = value as u8;
-                    buff[1] = (buff[1] & 0x0f) | (((value >> 8) & 0x0f) as u8);
+                    buff[1] = (buff[1] & 0xf0) | (((value >> 8) & 0x0f) as u8);
                 } else {
-                    buff[0] = (buff[0] & 0xf0) | ((value << 4) as u8);
-                    buff[1] = (value >> 8) as u8;
+                    buff[0] = (buff[0] & 0x0f) | (((value << 4) & 0xf0) as u8);
+                    buff[1] = (value >> 4) as u8;
                 }
 
-                self.sector.write(sector, offset, &buff)
+                self.table_write_bytes(sector, offset, &buff)
             }
         }
     }
 
+    /// Looks for a free cluster from `start_cluster` to the end of the FAT,
+    /// then wraps around to the first data cluster.
     fn table_find_free(&self, start_cluster: u32) -> Result<u32, FsErr> {
-        for cluster in start_cluster..self.table_clusters_count {
-            match self.table_get(cluster)? {
-                ClusterValue::Free => return Ok(cluster),
-                _ => {},
+        let start_cluster = if (2..self.table_clusters_count).contains(&start_cluster) {
+            start_cluster
+        } else {
+            2
+        };
+
+        for cluster in (start_cluster..self.table_clusters_count).chain(2..start_cluster) {
+            if let ClusterValue::Free = self.table_get(cluster)? {
+                self.next_free.set(cluster);
+                self.fs_info_dirty.set(true);
+                return Ok(cluster);
             }
         }
 
@@ -190,55 +676,76 @@ impl <'bd> Fs<'bd> {
         }
     }
 
+    /// Cuts or grows the chain starting at `cluster` to `count` clusters,
+    /// freeing the tail or appending new clusters. A count of 0 frees the
+    /// whole chain.
     pub fn table_chain_set_len(&self, cluster: u32, count: u32) -> Result<(), FsErr> {
-        todo!();
-    }
-    /*
-    pub fn table_chain_truncate(&self, cluster: u32, count: u32) -> Result<(), FsErr> {
-        assert_ne!(count, 0);
+        if count == 0 {
+            return self.table_chain_delete(cluster);
+        }
 
-        let count = count - 1;
         let mut cluster = cluster;
-        // skip clusters
-        for _ in 0..count {
+
+        for len in 1..count {
             match self.table_get(cluster)? {
                 ClusterValue::Next(n) => cluster = n,
-                // chain is shortter
-                ClusterValue::Last => return Ok(()),
+                ClusterValue::Last => {
+                    self.table_chain_extend(cluster, count - len)?;
+                    return Ok(());
+                },
                 ClusterValue::Free | ClusterValue::Bad => return Err(FsErr::FatTableError),
             }
         }
-        // truncate chain
+
         match self.table_get(cluster)? {
             ClusterValue::Next(n) => {
                 self.table_set(cluster, ClusterValue::Last)?;
-                cluster = n
+                self.table_chain_delete(n)
             },
-            // chain is shortter
-            ClusterValue::Last => return Ok(()),
-            ClusterValue::Free | ClusterValue::Bad => return Err(FsErr::FatTableError),
+            ClusterValue::Last => Ok(()),
+            ClusterValue::Free | ClusterValue::Bad => Err(FsErr::FatTableError),
         }
-        // free chain
-        self.table_chain_delete(cluster)
     }
-    */
+
     pub fn table_chain_create(&self, count: u32) -> Result<u32, FsErr> {
         assert_ne!(count, 0);
     
-        let first_cluster = self.table_find_free(0)?;
+        let first_cluster = self.table_find_free(self.next_free.get())?;
+        self.table_set(first_cluster, ClusterValue::Last)?;
         let mut cluster = first_cluster;
         let count = count - 1;
     
         for _ in 0..count {
-            let next_cluster = self.table_find_free(cluster + 1)?;
+            // every cluster is marked as soon as it is found, so the search
+            // can wrap around without handing out the same cluster twice
+            let next_cluster = match self.table_find_free(cluster + 1) {
+                Ok(next_cluster) => next_cluster,
+                Err(e) => {
+                    self.table_chain_delete(first_cluster)?;
+                    return Err(e);
+                },
+            };
+
+            self.table_set(next_cluster, ClusterValue::Last)?;
             self.table_set(cluster, ClusterValue::Next(next_cluster))?;
             cluster = next_cluster;
         }
     
-        self.table_set(cluster, ClusterValue::Last)?;
         Ok(first_cluster)
     }
 
+    pub fn table_chain_last(&self, cluster: u32) -> Result<u32, FsErr> {
+        let mut cluster = cluster;
+
+        loop {
+            match self.table_get(cluster)? {
+                ClusterValue::Next(n) => cluster = n,
+                ClusterValue::Last => return Ok(cluster),
+                ClusterValue::Free | ClusterValue::Bad => return Err(FsErr::FatTableError),
+            }
+        }
+    }
+
     pub fn table_chain_extend(&self, cluster: u32, count: u32) -> Result<u32, FsErr> {
         let extend_cluster = self.table_chain_create(count)?;
         self.table_set(cluster, ClusterValue::Next(extend_cluster))?;
@@ -249,8 +756,1339 @@ impl <'bd> Fs<'bd> {
         Sector::new(self.io)
     }
     */
-    pub fn cluster_to_sector(&self, cluster: u32) -> u32 {
-        todo!();
-        //self.data_area_first_sector + (cluster - self.root_cluster) * self.sectors_in_cluster
+    /// Data clusters are numbered from 2, the first one starts right after the
+    /// FATs (and the fixed root directory on FAT12/16).
+    pub fn cluster_to_sector(&self, cluster: u32) -> Result<u32, FsErr> {
+        if !(2..self.table_clusters_count).contains(&cluster) {
+            return Err(FsErr::FatTableError);
+        }
+
+        Ok(self.data_first_sector + (cluster - 2) * self.sectors_in_cluster)
+    }
+
+    /// Stream over the directory starting at `cluster`, 0 stands for the root
+    /// directory just like in `..` entries.
+    pub fn dir_stream(&self, cluster: u32) -> Stream<'_, 'bd, N> {
+        match cluster {
+            0 => Stream::root(self),
+            cluster => Stream::new(self, cluster),
+        }
+    }
+
+    /// Walks a `/` separated path from the root directory, `None` stands for
+    /// the root directory itself.
+    fn lookup(&self, path: &str) -> Result<Option<DirEntry>, FsErr> {
+        let mut entry: Option<DirEntry> = None;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let dir_cluster = match entry {
+                None => 0,
+                Some(ref entry) if entry.is_dir() => entry.first_cluster(),
+                Some(_) => return Err(FsErr::DirEntryNotDir),
+            };
+
+            let found = DirIterator::new(self.dir_stream(dir_cluster)).find_name(name);
+            entry = Some(found.ok_or(FsErr::FileOrFolderDesntExist)?);
+        }
+
+        Ok(entry)
+    }
+
+    /// Marks the short entry and its long name entries as deleted.
+    fn dir_remove_entry(&self, entry: &DirEntry) -> Result<(), FsErr> {
+        for index in entry.lfn_index()..=entry.index() {
+            let mut data = self.dir_entry_read(entry.dir_cluster(), index)?;
+            data[0] = DIR_ENTRY_DELETED;
+            self.dir_entry_write(entry.dir_cluster(), index, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Splits a path into the first cluster of its parent directory, which
+    /// must exist, and a valid name.
+    fn lookup_parent<'p>(&self, path: &'p str) -> Result<(u32, &'p str), FsErr> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rfind('/') {
+            Some(slash) => (&path[..slash], &path[(slash + 1)..]),
+            None => ("", path),
+        };
+
+        validate_name(name)?;
+
+        let dir_cluster = match self.lookup(parent)? {
+            None => 0,
+            Some(entry) if entry.is_dir() => self.dir_cluster_normalize(entry.first_cluster()),
+            Some(_) => return Err(FsErr::DirEntryNotDir),
+        };
+
+        Ok((dir_cluster, name))
+    }
+
+    /// Like `lookup_parent`, for a name that must not exist yet.
+    fn lookup_new<'p>(&self, path: &'p str) -> Result<(u32, &'p str), FsErr> {
+        let (dir_cluster, name) = self.lookup_parent(path)?;
+
+        if DirIterator::new(self.dir_stream(dir_cluster)).find_name(name).is_some() {
+            return Err(FsErr::AlreadyExists);
+        }
+
+        Ok((dir_cluster, name))
+    }
+
+    /// The FAT32 root directory is referred to as cluster 0 like on FAT12/16,
+    /// `..` entries must point to 0 for it.
+    fn dir_cluster_normalize(&self, dir_cluster: u32) -> u32 {
+        if self.fat_type == FatType::Fat32 && dir_cluster == self.bpb.root_cluster {
+            0
+        } else {
+            dir_cluster
+        }
+    }
+
+    /// Creates an empty file, its first cluster is allocated on the first
+    /// write. Fails if the file exists.
+    pub fn create(&self, path: &str) -> Result<File<'_, 'bd, N>, FsErr> {
+        let (dir_cluster, name) = self.lookup_new(path)?;
+
+        let mut entry = [0u8; 32];
+        entry[11] = Attributes::ARCHIVE.bits();
+        entry_set_created(&mut entry, self.now());
+
+        let index = self.dir_add_entry(dir_cluster, name, &mut entry)?;
+        Ok(File::new(Stream::new(self, 0), 0, dir_cluster, index))
+    }
+
+    pub fn remove_file(&self, path: &str) -> Result<(), FsErr> {
+        let entry = match self.lookup(path)? {
+            Some(entry) if !entry.is_dir() => entry,
+            _ => return Err(FsErr::DirEntryNotFile),
+        };
+
+        // the entry goes first, a crash in between only leaks clusters
+        self.dir_remove_entry(&entry)?;
+
+        // empty files have no chain
+        if entry.first_cluster() != 0 {
+            self.table_chain_delete(entry.first_cluster())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn create_dir(&self, path: &str) -> Result<(), FsErr> {
+        let (dir_cluster, name) = self.lookup_new(path)?;
+        let now = self.now();
+
+        let cluster = self.table_chain_create(1)?;
+        let mut entry = [0u8; 32];
+        entry[11] = Attributes::DIRECTORY.bits();
+        entry_set_created(&mut entry, now);
+
+        let result = self.cluster_zero(cluster).and_then(|_| {
+            let mut dot = entry;
+            dot[..11].copy_from_slice(b".          ");
+            entry_set_first_cluster(&mut dot, cluster);
+            self.dir_entry_write(cluster, 0, &dot)?;
+
+            dot[..11].copy_from_slice(b"..         ");
+            entry_set_first_cluster(&mut dot, dir_cluster);
+            self.dir_entry_write(cluster, 1, &dot)?;
+
+            entry_set_first_cluster(&mut entry, cluster);
+            self.dir_add_entry(dir_cluster, name, &mut entry)
+        });
+
+        if let Err(e) = result {
+            self.table_chain_delete(cluster)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Directory entry of `path` that can be removed, not the root and not
+    /// a dot entry.
+    fn lookup_dir_to_remove(&self, path: &str) -> Result<DirEntry, FsErr> {
+        match self.lookup(path)? {
+            Some(entry) if entry.is_dot() => Err(FsErr::InvalidName),
+            // a directory without a cluster would read as the root
+            Some(entry) if entry.is_dir() && entry.first_cluster() == 0 => Err(FsErr::FatTableError),
+            Some(entry) if entry.is_dir() => Ok(entry),
+            Some(_) => Err(FsErr::DirEntryNotDir),
+            None => Err(FsErr::InvalidName),
+        }
+    }
+
+    /// Removes an empty directory.
+    pub fn remove_dir(&self, path: &str) -> Result<(), FsErr> {
+        let entry = self.lookup_dir_to_remove(path)?;
+
+        let mut empty = true;
+        self.dir_for_each(entry.first_cluster(), |_, data| {
+            let entry = DirEntry::new(data);
+            // stray long name entries and labels hold no file
+            empty = is_long_name_entry(&data) || entry.is_volume_id() || entry.is_dot();
+            Ok(empty)
+        })?;
+
+        if !empty {
+            return Err(FsErr::DirNotEmpty);
+        }
+
+        self.dir_remove_entry(&entry)?;
+        self.table_chain_delete(entry.first_cluster())
+    }
+
+    /// Removes a directory and everything in it.
+    pub fn remove_dir_all(&self, path: &str) -> Result<(), FsErr> {
+        let entry = self.lookup_dir_to_remove(path)?;
+
+        self.dir_remove_contents(entry.first_cluster())?;
+        self.dir_remove_entry(&entry)?;
+        self.table_chain_delete(entry.first_cluster())
     }
-}
\ No newline at end of file
+
+    /// Empties the directory at `top` and everything below it. Walks the tree
+    /// without recursion, a subdirectory is emptied first and its entry is
+    /// removed when the walk comes back up through `..`.
+    fn dir_remove_contents(&self, top: u32) -> Result<(), FsErr> {
+        let mut dir = top;
+        let mut emptied = None;
+
+        loop {
+            let mut child = None;
+
+            self.dir_for_each(dir, |index, mut data| {
+                let entry = DirEntry::new(data);
+                // long name entries and labels only need to be marked
+                let file = !is_long_name_entry(&data) && !entry.is_volume_id();
+
+                if file && entry.is_dot() {
+                    return Ok(true);
+                }
+
+                if file && entry.is_dir() && Some(entry.first_cluster()) != emptied {
+                    child = Some(entry.first_cluster());
+                    return Ok(false);
+                }
+
+                data[0] = DIR_ENTRY_DELETED;
+                self.dir_entry_write(dir, index, &data)?;
+
+                if file && entry.first_cluster() != 0 {
+                    self.table_chain_delete(entry.first_cluster())?;
+                }
+
+                Ok(true)
+            })?;
+
+            match child {
+                Some(child) => {
+                    // a directory whose `..` doesn't lead back here could be
+                    // one of its own ancestors and would be walked forever
+                    if child == 0 || child == top {
+                        return Err(FsErr::FatTableError);
+                    }
+
+                    let dot_dot = DirEntry::new(self.dir_entry_read(child, 1)?);
+
+                    if !dot_dot.is_dot() || dot_dot.first_cluster() != dir {
+                        return Err(FsErr::FatTableError);
+                    }
+
+                    dir = child;
+                    emptied = None;
+                },
+                None if dir == top => return Ok(()),
+                None => {
+                    // `..` was checked on the way down
+                    emptied = Some(dir);
+                    dir = DirEntry::new(self.dir_entry_read(dir, 1)?).first_cluster();
+                },
+            }
+        }
+    }
+
+    /// Moves an entry to another name or directory without copying its data.
+    /// Fails if `to` exists, unless only the case of the name changes.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), FsErr> {
+        self.rename_entry(from, to, false)
+    }
+
+    /// Like `rename`, an existing file at `to` is replaced. An existing
+    /// directory is not.
+    pub fn rename_replace(&self, from: &str, to: &str) -> Result<(), FsErr> {
+        self.rename_entry(from, to, true)
+    }
+
+    fn rename_entry(&self, from: &str, to: &str, replace: bool) -> Result<(), FsErr> {
+        let entry = match self.lookup(from)? {
+            Some(entry) if !entry.is_dot() => entry,
+            _ => return Err(FsErr::InvalidName),
+        };
+        let (dir_cluster, name) = self.lookup_parent(to)?;
+        let same_dir = self.dir_cluster_normalize(entry.dir_cluster()) == dir_cluster;
+
+        if entry.is_dir() {
+            self.check_not_in_subtree(dir_cluster, entry.first_cluster())?;
+        }
+
+        let existing = DirIterator::new(self.dir_stream(dir_cluster)).find_name(name);
+        // renaming to itself, only the case of the name may change
+        let itself = matches!(existing, Some(ref existing) if same_dir && existing.index() == entry.index());
+
+        if itself && entry.name() == name {
+            return Ok(());
+        }
+
+        let replaced = existing.filter(|_| !itself);
+
+        if let Some(ref existing) = replaced {
+            if !replace || existing.is_dir() || entry.is_dir() {
+                return Err(FsErr::AlreadyExists);
+            }
+        }
+
+        let mut data = *entry.data();
+        // the NT lower case flags belong to the old short name
+        data[12] = 0;
+
+        // the new entry goes first, nothing is lost if there is no room for it
+        // and a crash in between leaves both names rather than none
+        let alias_owner = if itself { Some(&entry) } else { replaced.as_ref() };
+
+        match alias_owner {
+            Some(owner) => {
+                // the short name of the entry going away is kept, which also
+                // keeps the new one from getting a numeric tail
+                let mut alias = [0u8; 11];
+                alias.copy_from_slice(&owner.data()[..11]);
+
+                if alias[0] == DIR_ENTRY_E5_ESCAPE {
+                    alias[0] = DIR_ENTRY_DELETED;
+                }
+
+                let short_name = ShortName::new(name)?;
+                let long_name = !short_name.is_exact() || &alias != short_name.basis();
+                self.dir_write_entry(dir_cluster, name, &alias, long_name, &mut data)?;
+            },
+            None => {
+                self.dir_add_entry(dir_cluster, name, &mut data)?;
+            },
+        }
+
+        if let Some(existing) = replaced {
+            self.dir_remove_entry(&existing)?;
+
+            if existing.first_cluster() != 0 {
+                self.table_chain_delete(existing.first_cluster())?;
+            }
+        }
+
+        self.dir_remove_entry(&entry)?;
+
+        if entry.is_dir() && !same_dir {
+            self.dir_entry_set_first_cluster(entry.first_cluster(), 1, dir_cluster)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the directory at `dir_cluster` is `ancestor` or lies below
+    /// it, following `..` entries up to the root.
+    fn check_not_in_subtree(&self, dir_cluster: u32, ancestor: u32) -> Result<(), FsErr> {
+        let mut cluster = dir_cluster;
+
+        // bounded in case `..` entries form a loop
+        for _ in 0..self.table_clusters_count {
+            if cluster == ancestor {
+                return Err(FsErr::MoveIntoSubdir);
+            }
+
+            if cluster == 0 {
+                return Ok(());
+            }
+
+            let dot_dot = DirEntry::new(self.dir_entry_read(cluster, 1)?);
+            cluster = self.dir_cluster_normalize(dot_dot.first_cluster());
+        }
+
+        Err(FsErr::FatTableError)
+    }
+
+    /// Opens an existing file for reading.
+    pub fn open(&self, path: &str) -> Result<File<'_, 'bd, N>, FsErr> {
+        OpenOptions::new().read(true).open(self, path)
+    }
+
+    pub(crate) fn open_with_options(&self, path: &str, options: &OpenOptions) -> Result<File<'_, 'bd, N>, FsErr> {
+        options.validate()?;
+
+        let entry = match self.lookup(path) {
+            Ok(Some(_)) if options.create_new => return Err(FsErr::AlreadyExists),
+            Ok(Some(entry)) if !entry.is_dir() => entry,
+            Ok(_) => return Err(FsErr::DirEntryNotFile),
+            Err(FsErr::FileOrFolderDesntExist) if options.create || options.create_new => {
+                let mut file = self.create(path)?;
+                file.set_mode(options);
+                return Ok(file);
+            },
+            Err(e) => return Err(e),
+        };
+
+        if options.is_writable() && entry.attributes().contains(Attributes::READ_ONLY) {
+            return Err(FsErr::AccessDenied);
+        }
+
+        let stream = Stream::new(self, entry.first_cluster());
+        let mut file = File::new(stream, entry.size(), entry.dir_cluster(), entry.index());
+        file.set_mode(options);
+
+        if options.truncate && entry.size() != 0 {
+            file.resize(0)?;
+        }
+
+        Ok(file)
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<Metadata, FsErr> {
+        Ok(self.lookup(path)?.map_or_else(Metadata::root, |entry| entry.metadata()))
+    }
+
+    /// Changes the read-only, hidden, system and archive bits, the others
+    /// in `attributes` are ignored.
+    pub fn set_attributes(&self, path: &str, attributes: Attributes) -> Result<(), FsErr> {
+        let entry = match self.lookup(path)? {
+            Some(entry) if !entry.is_dot() => entry,
+            _ => return Err(FsErr::InvalidName),
+        };
+
+        let changeable = Attributes::READ_ONLY | Attributes::HIDDEN | Attributes::SYSTEM | Attributes::ARCHIVE;
+        let mut data = *entry.data();
+        data[11] = (data[11] & !changeable.bits()) | (attributes.bits() & changeable.bits());
+        self.dir_entry_write(entry.dir_cluster(), entry.index(), &data)
+    }
+
+    pub fn volume_id(&self) -> u32 {
+        self.bpb.volume_id
+    }
+
+    /// Index of the volume-ID entry of the root directory.
+    fn root_volume_entry(&self) -> Result<Option<u32>, FsErr> {
+        let mut stream = self.dir_stream(0);
+        let mut data = [0u8; 32];
+        let mut index = 0;
+
+        loop {
+            match stream.read(&mut data) {
+                Ok(_) => {},
+                Err(FsErr::EndOfStream) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            if data[0] == DIR_ENTRY_END {
+                return Ok(None);
+            }
+
+            let attributes = Attributes::from_bits(data[11]);
+            if data[0] != DIR_ENTRY_DELETED && !attributes.contains(Attributes::LONG_NAME) && attributes.contains(Attributes::VOLUME_ID) {
+                return Ok(Some(index));
+            }
+
+            index += 1;
+        }
+    }
+
+    /// Space padded volume label, `NO NAME` when there is none. The root
+    /// directory entry wins over the boot sector copy, as on Windows.
+    pub fn label(&self) -> Result<[u8; 11], FsErr> {
+        let mut label = NO_LABEL;
+
+        if let Some(index) = self.root_volume_entry()? {
+            label.copy_from_slice(&self.dir_entry_read(0, index)?[..11]);
+            return Ok(label);
+        }
+
+        let ext = ext_boot_offset(self.fat_type == FatType::Fat32);
+        let mut ext_boot = [0u8; 18];
+        self.sector.read(0, ext, &mut ext_boot)?;
+
+        if ext_boot[2] == EXT_BOOT_SIGNATURE {
+            label.copy_from_slice(&ext_boot[7..]);
+        }
+
+        Ok(label)
+    }
+
+    /// Sets the label in the root directory and in the boot sector, an empty
+    /// label removes it. Lower case letters are stored in upper case.
+    pub fn set_label(&self, label: &str) -> Result<(), FsErr> {
+        let mut padded = [b' '; 11];
+
+        if label.chars().count() > padded.len() || label.starts_with(' ') {
+            return Err(FsErr::InvalidName);
+        }
+
+        for (c, byte) in label.chars().zip(padded.iter_mut()) {
+            *byte = match c {
+                ' ' => b' ',
+                c => short_name_byte(c).ok_or(FsErr::InvalidName)?,
+            };
+        }
+
+        // the root directory may be full, it goes first
+        match (self.root_volume_entry()?, label.is_empty()) {
+            (Some(index), true) => {
+                let mut data = self.dir_entry_read(0, index)?;
+                data[0] = DIR_ENTRY_DELETED;
+                self.dir_entry_write(0, index, &data)?;
+            },
+            (Some(index), false) => {
+                let mut data = self.dir_entry_read(0, index)?;
+                data[..11].copy_from_slice(&padded);
+                entry_set_modified(&mut data, self.now());
+                self.dir_entry_write(0, index, &data)?;
+            },
+            (None, true) => {},
+            (None, false) => {
+                let mut data = [0u8; 32];
+                data[..11].copy_from_slice(&padded);
+                data[11] = Attributes::VOLUME_ID.bits();
+                entry_set_modified(&mut data, self.now());
+
+                let index = self.dir_find_free_entries(0, 1)?;
+                self.dir_entry_write(0, index, &data)?;
+            },
+        }
+
+        let boot_label = if label.is_empty() { NO_LABEL } else { padded };
+        let ext = ext_boot_offset(self.fat_type == FatType::Fat32);
+        let mut signature = [0u8; 1];
+        self.sector.read(0, ext + 2, &mut signature)?;
+
+        if signature[0] == EXT_BOOT_SIGNATURE {
+            self.sector.write(0, ext + 7, &boot_label)?;
+
+            if self.fat_type == FatType::Fat32 && self.bpb.backup_boot_sector != 0 {
+                self.sector.write(self.bpb.backup_boot_sector, ext + 7, &boot_label)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn open_dir(&self, path: &str) -> Result<DirIterator<'_, 'bd, N>, FsErr> {
+        match self.lookup(path)? {
+            None => Ok(DirIterator::new(Stream::root(self))),
+            Some(entry) if entry.is_dir() => Ok(DirIterator::new(self.dir_stream(entry.first_cluster()))),
+            Some(_) => Err(FsErr::DirEntryNotDir),
+        }
+    }
+
+    /// Stamps the access date of an entry that was read from, as the access
+    /// time policy allows.
+    pub(crate) fn dir_entry_accessed(&self, dir_cluster: u32, index: u32) -> Result<(), FsErr> {
+        if self.access_time == AccessTime::Never {
+            return Ok(());
+        }
+
+        let mut data = self.dir_entry_read(dir_cluster, index)?;
+        let now = self.now();
+        let (today, _, _) = now.encode();
+        let accessed = u16_from_bytes(&data[18..]);
+
+        if accessed == today {
+            return Ok(());
+        }
+
+        if self.access_time == AccessTime::Relatime {
+            let modified = u16_from_bytes(&data[24..]);
+            let stale = DateTime::decode(accessed, 0, 0).is_none_or(|accessed| now.days() > accessed.days() + 1);
+
+            if accessed > modified && !stale {
+                return Ok(());
+            }
+        }
+
+        entry_set_accessed(&mut data, now);
+        self.dir_entry_write(dir_cluster, index, &data)
+    }
+
+    pub(crate) fn dir_entry_set_first_cluster(&self, dir_cluster: u32, index: u32, cluster: u32) -> Result<(), FsErr> {
+        let mut data = self.dir_entry_read(dir_cluster, index)?;
+        entry_set_first_cluster(&mut data, cluster);
+        self.dir_entry_write(dir_cluster, index, &data)
+    }
+
+    /// Stores the first cluster and size of a file that was written to, and
+    /// stamps its modification time.
+    pub(crate) fn dir_entry_modified(&self, dir_cluster: u32, index: u32, cluster: u32, size: u32) -> Result<(), FsErr> {
+        let mut data = self.dir_entry_read(dir_cluster, index)?;
+        entry_set_first_cluster(&mut data, cluster);
+        entry_set_size(&mut data, size);
+        entry_set_modified(&mut data, self.now());
+        // backup tools clear the archive bit, any change sets it again
+        data[11] |= Attributes::ARCHIVE.bits();
+        self.dir_entry_write(dir_cluster, index, &data)
+    }
+
+    fn is_fixed_root(&self, dir_cluster: u32) -> bool {
+        dir_cluster == 0 && self.fat_type != FatType::Fat32
+    }
+
+    /// Sector and offset of entry `index` of the directory at `dir_cluster`.
+    fn dir_entry_position(&self, dir_cluster: u32, index: u32) -> Result<(u32, usize), FsErr> {
+        let offset = index * DIR_ENTRY_SIZE;
+
+        if self.is_fixed_root(dir_cluster) {
+            if index >= self.bpb.root_entry_count {
+                return Err(FsErr::OutOfRange);
+            }
+
+            let sector = self.bpb.root_dir_first_sector() + offset / self.sector_size;
+            return Ok((sector, (offset % self.sector_size) as usize));
+        }
+
+        let first_cluster = if dir_cluster == 0 { self.bpb.root_cluster } else { dir_cluster };
+        let cluster = self.table_chain_skip(first_cluster, offset / self.cluster_size)?;
+        let sector = self.cluster_to_sector(cluster)? + (offset % self.cluster_size) / self.sector_size;
+        Ok((sector, (offset % self.sector_size) as usize))
+    }
+
+    pub fn dir_entry_read(&self, dir_cluster: u32, index: u32) -> Result<[u8; 32], FsErr> {
+        let (sector, offset) = self.dir_entry_position(dir_cluster, index)?;
+        let mut data = [0u8; 32];
+        self.sector.read(sector, offset, &mut data)?;
+        Ok(data)
+    }
+
+    pub fn dir_entry_write(&self, dir_cluster: u32, index: u32, data: &[u8; 32]) -> Result<(), FsErr> {
+        let (sector, offset) = self.dir_entry_position(dir_cluster, index)?;
+        self.sector.write(sector, offset, data)
+    }
+
+    /// Appends a zeroed cluster to the directory chain, a directory must
+    /// end with free entries rather than stale data.
+    fn dir_extend(&self, dir_cluster: u32) -> Result<(), FsErr> {
+        let first_cluster = if dir_cluster == 0 { self.bpb.root_cluster } else { dir_cluster };
+        let last = self.table_chain_last(first_cluster)?;
+        let cluster = self.table_chain_extend(last, 1)?;
+        self.cluster_zero(cluster)
+    }
+
+    pub fn cluster_zero(&self, cluster: u32) -> Result<(), FsErr> {
+        let zero = [0u8; BLOCK_MAX_SIZE];
+        let first_sector = self.cluster_to_sector(cluster)?;
+
+        for sector in first_sector..(first_sector + self.sectors_in_cluster) {
+            self.sector.write(sector, 0, &zero[..(self.sector_size as usize)])?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with the index and data of every used entry of the directory,
+    /// long name entries and the label included, until it returns false.
+    /// Unlike `DirIterator` read and FAT errors are returned.
+    fn dir_for_each(&self, dir_cluster: u32, mut f: impl FnMut(u32, [u8; 32]) -> Result<bool, FsErr>) -> Result<(), FsErr> {
+        let mut stream = self.dir_stream(dir_cluster);
+        let mut data = [0u8; 32];
+        let mut index = 0;
+
+        loop {
+            match stream.read(&mut data) {
+                Ok(_) => {},
+                Err(FsErr::EndOfStream) => break,
+                Err(e) => return Err(e),
+            }
+
+            if data[0] == DIR_ENTRY_END {
+                break;
+            }
+
+            if data[0] != DIR_ENTRY_DELETED && !f(index, data)? {
+                break;
+            }
+
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Index of the first of `count` contiguous free entries, the directory
+    /// grows when it has no such run.
+    fn dir_find_free_entries(&self, dir_cluster: u32, count: u32) -> Result<u32, FsErr> {
+        let mut stream = self.dir_stream(dir_cluster);
+        let mut data = [0u8; 32];
+        let mut index = 0;
+        let mut run_first = 0;
+        let mut run_len = 0;
+
+        loop {
+            match stream.read(&mut data) {
+                Ok(_) => {},
+                Err(FsErr::EndOfStream) => break,
+                Err(e) => return Err(e),
+            }
+
+            if data[0] == DIR_ENTRY_END || data[0] == DIR_ENTRY_DELETED {
+                if run_len == 0 {
+                    run_first = index;
+                }
+
+                run_len += 1;
+
+                if run_len == count {
+                    return Ok(run_first);
+                }
+            } else {
+                run_len = 0;
+            }
+
+            index += 1;
+        }
+
+        if run_len == 0 {
+            run_first = index;
+        }
+
+        if self.is_fixed_root(dir_cluster) {
+            return Err(FsErr::RootDirFull);
+        }
+
+        if run_first + count > DIR_ENTRIES_MAX {
+            return Err(FsErr::DirFull);
+        }
+
+        let entries_in_cluster = self.cluster_size / DIR_ENTRY_SIZE;
+        while run_len < count {
+            self.dir_extend(dir_cluster)?;
+            run_len += entries_in_cluster;
+        }
+
+        Ok(run_first)
+    }
+
+    /// Picks an 8.3 name for `name` that no entry of the directory uses yet.
+    /// A scan records the tails in use for a window of candidates, one scan
+    /// does unless hundreds of names share the basis.
+    fn dir_short_name(&self, dir_cluster: u32, name: &str) -> Result<(ShortName, [u8; 11]), FsErr> {
+        let short_name = ShortName::new(name)?;
+        let mut first = 1;
+
+        while first <= SHORT_NAME_TAIL_MAX {
+            let mut basis_used = false;
+            let mut tails_used = [0u64; (SHORT_NAME_TAILS_PER_SCAN / 64) as usize];
+
+            self.dir_for_each(dir_cluster, |_, data| {
+                if is_long_name_entry(&data) || DirEntry::new(data).is_volume_id() {
+                    return Ok(true);
+                }
+
+                let mut alias = [0u8; 11];
+                alias.copy_from_slice(&data[..11]);
+
+                if alias[0] == DIR_ENTRY_E5_ESCAPE {
+                    alias[0] = DIR_ENTRY_DELETED;
+                }
+
+                basis_used |= &alias == short_name.basis();
+
+                if let Some(n) = short_name.tail_of(&alias).and_then(|n| n.checked_sub(first)) {
+                    if n < SHORT_NAME_TAILS_PER_SCAN {
+                        tails_used[(n / 64) as usize] |= 1 << (n % 64);
+                    }
+                }
+
+                Ok(true)
+            })?;
+
+            if first == 1 && !short_name.is_lossy() && !basis_used {
+                let basis = *short_name.basis();
+                return Ok((short_name, basis));
+            }
+
+            let free = (0..SHORT_NAME_TAILS_PER_SCAN).find(|&n| tails_used[(n / 64) as usize] & (1 << (n % 64)) == 0);
+
+            if let Some(n) = free.map(|n| first + n).filter(|&n| n <= SHORT_NAME_TAIL_MAX) {
+                let alias = short_name.with_tail(n);
+                return Ok((short_name, alias));
+            }
+
+            first += SHORT_NAME_TAILS_PER_SCAN;
+        }
+
+        Err(FsErr::DirFull)
+    }
+
+    /// Writes `entry` to the directory under `name`, preceded by its long name
+    /// entries unless the name is a plain upper case 8.3 name. Bytes 0..11 of
+    /// `entry` are set to the generated alias. Returns the index of the short
+    /// entry.
+    pub fn dir_add_entry(&self, dir_cluster: u32, name: &str, entry: &mut [u8; 32]) -> Result<u32, FsErr> {
+        let (short_name, alias) = self.dir_short_name(dir_cluster, name)?;
+        let long_name = !short_name.is_exact() || &alias != short_name.basis();
+        self.dir_write_entry(dir_cluster, name, &alias, long_name, entry)
+    }
+
+    /// Writes `entry` with the given alias into free slots of the directory,
+    /// preceded by the long name entries of `name` when `long_name` is set.
+    fn dir_write_entry(&self, dir_cluster: u32, name: &str, alias: &[u8; 11], long_name: bool, entry: &mut [u8; 32]) -> Result<u32, FsErr> {
+        let lfn_count = if long_name { lfn_entries_count(name)? } else { 0 };
+
+        entry[..11].copy_from_slice(alias);
+        // a leading 0xE5 would read as a deleted entry
+        if entry[0] == DIR_ENTRY_DELETED {
+            entry[0] = DIR_ENTRY_E5_ESCAPE;
+        }
+
+        let first = self.dir_find_free_entries(dir_cluster, lfn_count + 1)?;
+        let checksum = lfn_checksum(&entry[..11]);
+
+        // the last part of the name comes first
+        for i in 0..lfn_count {
+            let ordinal = lfn_count - i;
+            self.dir_entry_write(dir_cluster, first + i, &lfn_entry(name, ordinal, lfn_count, checksum))?;
+        }
+
+        self.dir_entry_write(dir_cluster, first + lfn_count, entry)?;
+        Ok(first + lfn_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use crate::fs::ram::RamDisk;
+
+    fn write_file(fs: &Fs, path: &str, data: &[u8]) {
+        let mut file = fs.create(path).unwrap();
+        file.write(data).unwrap();
+        file.close().unwrap();
+    }
+
+    fn read_file(fs: &Fs, path: &str) -> Result<Vec<u8>, FsErr> {
+        let mut file = fs.open(path)?;
+        let mut data = vec![0u8; file.size() as usize];
+        let mut len = 0;
+
+        while len < data.len() {
+            len += file.read(&mut data[len..])?;
+        }
+
+        Ok(data)
+    }
+
+    /// Fills the fixed root directory with empty files.
+    fn fill_root(fs: &Fs) {
+        for i in 0.. {
+            match fs.create(&format!("/F{}.TXT", i)) {
+                Ok(file) => file.close().unwrap(),
+                Err(FsErr::RootDirFull) => return,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn open_walks_path() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        fs.create_dir("/Some Dir").unwrap();
+        fs.create_dir("/Some Dir/inner").unwrap();
+        write_file(&fs, "/Some Dir/inner/File.txt", b"nested");
+
+        assert_eq!(read_file(&fs, "/some dir/INNER/file.TXT").unwrap(), b"nested");
+        assert_eq!(read_file(&fs, "some dir/inner/file.txt").unwrap(), b"nested");
+        assert_eq!(read_file(&fs, "/Some Dir/nope.txt").err(), Some(FsErr::FileOrFolderDesntExist));
+        assert_eq!(read_file(&fs, "/Some Dir/inner/File.txt/x").err(), Some(FsErr::DirEntryNotDir));
+        assert_eq!(read_file(&fs, "/Some Dir").err(), Some(FsErr::DirEntryNotFile));
+
+        // the iterator's own find is not shadowed
+        let entry = fs.open_dir("/Some Dir/inner").unwrap().find(|entry| entry.size() == 6);
+        assert_eq!(entry.map(|entry| entry.name() == "File.txt"), Some(true));
+        assert!(fs.open_dir("/Some Dir").unwrap().find_name("INNER").is_some());
+    }
+
+    fn short_name_of(fs: &Fs, path: &str, name: &str) -> [u8; 11] {
+        let entry = fs.open_dir(path).unwrap().find_name(name).unwrap();
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&entry.data()[..11]);
+        short_name
+    }
+
+    #[test]
+    fn short_name_aliases() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        fs.create_dir("/d").unwrap();
+
+        for name in ["README.TXT", "readme.md", "long file name 1.txt", "long file name 2.txt", "long file name 3.txt"].iter() {
+            fs.create(&format!("/d/{}", name)).unwrap().close().unwrap();
+        }
+
+        assert_eq!(&short_name_of(&fs, "/d", "README.TXT"), b"README  TXT");
+        assert_eq!(&short_name_of(&fs, "/d", "readme.md"), b"README  MD ");
+        assert_eq!(&short_name_of(&fs, "/d", "long file name 1.txt"), b"LONGFI~1TXT");
+        assert_eq!(&short_name_of(&fs, "/d", "long file name 3.txt"), b"LONGFI~3TXT");
+
+        // a plain 8.3 name takes a single entry
+        let entry = fs.open_dir("/d").unwrap().find_name("README.TXT").unwrap();
+        assert_eq!(entry.lfn_index(), entry.index());
+
+        // a freed tail is taken again
+        fs.remove_file("/d/long file name 2.txt").unwrap();
+        fs.create("/d/long file name 4.txt").unwrap().close().unwrap();
+        assert_eq!(&short_name_of(&fs, "/d", "long file name 4.txt"), b"LONGFI~2TXT");
+
+        // more aliases than one scan looks for
+        for i in 0..300 {
+            fs.create(&format!("/d/long file name {}.log", i)).unwrap().close().unwrap();
+        }
+
+        assert_eq!(&short_name_of(&fs, "/d", "long file name 299.log"), b"LONG~300LOG");
+    }
+
+    struct Clock(Cell<DateTime>);
+
+    impl TimeProvider for Clock {
+        fn now(&self) -> DateTime {
+            self.0.get()
+        }
+    }
+
+    fn at(day: u8, hour: u8) -> DateTime {
+        DateTime { year: 2024, month: 5, day, hour, minute: 30, second: 15, millisecond: 500 }
+    }
+
+    fn read_byte(fs: &Fs, path: &str) {
+        let mut buff = [0u8; 1];
+        fs.open(path).unwrap().read(&mut buff).unwrap();
+    }
+
+    #[test]
+    fn timestamps() {
+        let clock = Clock(Cell::new(at(1, 10)));
+        let disk = RamDisk::formatted(2880, None);
+        let mut fs = Fs::mount(&disk).unwrap();
+        fs.set_time_provider(&clock);
+
+        write_file(&fs, "/A.TXT", b"a");
+        let metadata = fs.metadata("/A.TXT").unwrap();
+        assert_eq!(metadata.created, Some(at(1, 10)));
+        assert_eq!(metadata.modified, Some(DateTime { millisecond: 0, second: 14, ..at(1, 10) }));
+
+        clock.0.set(at(2, 9));
+        let mut file = OpenOptions::new().write(true).open(&fs, "/A.TXT").unwrap();
+        file.write(b"b").unwrap();
+        file.close().unwrap();
+        let metadata = fs.metadata("/A.TXT").unwrap();
+        assert_eq!(metadata.created, Some(at(1, 10)));
+        assert_eq!(metadata.modified.map(|modified| modified.day), Some(2));
+        assert_eq!(metadata.accessed.map(|accessed| (accessed.day, accessed.hour)), Some((2, 0)));
+    }
+
+    #[test]
+    fn access_time() {
+        let clock = Clock(Cell::new(at(1, 10)));
+        let disk = RamDisk::formatted(2880, None);
+        let mut fs = Fs::mount(&disk).unwrap();
+        fs.set_time_provider(&clock);
+        write_file(&fs, "/A.TXT", b"a");
+        let accessed = |fs: &Fs| fs.metadata("/A.TXT").unwrap().accessed.unwrap().day;
+
+        // not newer than the modification yet
+        clock.0.set(at(2, 10));
+        read_byte(&fs, "/A.TXT");
+        assert_eq!(accessed(&fs), 2);
+
+        // newer and less than a day old
+        clock.0.set(at(3, 10));
+        read_byte(&fs, "/A.TXT");
+        assert_eq!(accessed(&fs), 2);
+
+        clock.0.set(at(5, 10));
+        read_byte(&fs, "/A.TXT");
+        assert_eq!(accessed(&fs), 5);
+
+        fs.set_access_time(AccessTime::Never);
+        clock.0.set(at(20, 10));
+        read_byte(&fs, "/A.TXT");
+        assert_eq!(accessed(&fs), 5);
+
+        fs.set_access_time(AccessTime::Always);
+        clock.0.set(at(21, 10));
+        read_byte(&fs, "/A.TXT");
+        assert_eq!(accessed(&fs), 21);
+    }
+
+    /// Device whose `bad` blocks can't be read.
+    struct BadBlocks<'a> {
+        disk: &'a RamDisk,
+        bad: &'a [u32],
+    }
+
+    impl BlockDeviceIo for BadBlocks<'_> {
+        fn block_size(&self) -> u32 {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> u32 {
+            self.disk.block_count()
+        }
+
+        fn read(&self, block: u32, data: &mut [u8]) -> Result<(), FsErr> {
+            if self.bad.contains(&block) {
+                return Err(FsErr::ReadError);
+            }
+
+            self.disk.read(block, data)
+        }
+
+        fn write(&self, block: u32, data: &[u8]) -> Result<(), FsErr> {
+            self.disk.write(block, data)
+        }
+    }
+
+    fn table_copy(disk: &RamDisk, fs_bpb: &Bpb, copy: u32) -> Vec<u8> {
+        let first = fs_bpb.reserved_sectors + copy * fs_bpb.table_size;
+        let mut data = vec![0u8; (fs_bpb.table_size * 512) as usize];
+
+        for (i, block) in data.chunks_mut(512).enumerate() {
+            disk.read(first + i as u32, block).unwrap();
+        }
+
+        data
+    }
+
+    #[test]
+    fn table_copies_stay_in_sync() {
+        for (block_count, fat_type) in [(2880, None), (20000, None), (600000, Some(FatType::Fat32))] {
+            let disk = RamDisk::formatted(block_count, fat_type);
+            let fs = Fs::mount(&disk).unwrap();
+            let cluster = fs.table_chain_create(3).unwrap();
+            fs.table_chain_delete(fs.table_chain_create(2).unwrap()).unwrap();
+            let bpb = fs.bpb;
+            fs.unmount().unwrap();
+
+            assert_eq!(table_copy(&disk, &bpb, 0), table_copy(&disk, &bpb, 1));
+
+            let fs = Fs::mount(&disk).unwrap();
+            assert!(matches!(fs.table_get(cluster).unwrap(), ClusterValue::Next(_)));
+        }
+    }
+
+    #[test]
+    fn table_read_error_uses_mirror() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        let cluster = fs.table_chain_create(3).unwrap();
+        let bpb = fs.bpb;
+        fs.unmount().unwrap();
+
+        let first_copy = [bpb.reserved_sectors];
+        let bad = BadBlocks { disk: &disk, bad: &first_copy };
+        let fs = Fs::mount(&bad).unwrap();
+        assert!(matches!(fs.table_get(cluster).unwrap(), ClusterValue::Next(next) if next == cluster + 1));
+        assert!(matches!(fs.table_get(cluster + 2).unwrap(), ClusterValue::Last));
+
+        let both_copies = [bpb.reserved_sectors, bpb.reserved_sectors + bpb.table_size];
+        let bad = BadBlocks { disk: &disk, bad: &both_copies };
+        let fs = Fs::mount(&bad).unwrap();
+        assert_eq!(fs.table_get(cluster).err(), Some(FsErr::ReadError));
+    }
+
+    #[test]
+    fn table_mirroring_disabled() {
+        let disk = RamDisk::formatted(600000, Some(FatType::Fat32));
+        // ExtFlags: only the second FAT is active
+        disk.put(0, 40, &(EXT_FLAGS_NO_MIRRORING | 1).to_le_bytes());
+
+        let fs = Fs::mount(&disk).unwrap();
+        let bpb = fs.bpb;
+        let before = table_copy(&disk, &bpb, 0);
+        fs.table_chain_create(3).unwrap();
+        fs.unmount().unwrap();
+
+        assert_eq!(table_copy(&disk, &bpb, 0), before);
+        assert_ne!(table_copy(&disk, &bpb, 1), before);
+    }
+
+    #[test]
+    fn corrupt_cluster_numbers() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/A.TXT", &[1u8; 2000]);
+        let entry = fs.open_dir("/").unwrap().find_name("A.TXT").unwrap();
+        let set_first_cluster = |cluster| {
+            let mut data = *entry.data();
+            entry_set_first_cluster(&mut data, cluster);
+            fs.dir_entry_write(entry.dir_cluster(), entry.index(), &data).unwrap();
+        };
+
+        set_first_cluster(0);
+        assert_eq!(read_file(&fs, "/A.TXT").err(), Some(FsErr::FatTableError));
+
+        set_first_cluster(1);
+        assert_eq!(read_file(&fs, "/A.TXT").err(), Some(FsErr::FatTableError));
+
+        // the chain leads out of the data area after the first cluster
+        set_first_cluster(entry.first_cluster());
+        fs.table_set(entry.first_cluster(), ClusterValue::Next(0xFF0)).unwrap();
+        assert_eq!(fs.table_get(entry.first_cluster()).err(), Some(FsErr::FatTableError));
+        assert_eq!(read_file(&fs, "/A.TXT").err(), Some(FsErr::FatTableError));
+    }
+
+    #[test]
+    fn remove_file() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        let free = fs.free_clusters_count().unwrap();
+
+        write_file(&fs, "/a file with a long name.txt", &[0xAB; 3000]);
+        fs.create("/EMPTY.TXT").unwrap().close().unwrap();
+        fs.create_dir("/dir").unwrap();
+        assert!(fs.free_clusters_count().unwrap() < free - 1);
+
+        fs.remove_file("/A FILE WITH A LONG NAME.TXT").unwrap();
+        fs.remove_file("/empty.txt").unwrap();
+        assert_eq!(read_file(&fs, "/a file with a long name.txt").err(), Some(FsErr::FileOrFolderDesntExist));
+        assert_eq!(read_file(&fs, "/EMPTY.TXT").err(), Some(FsErr::FileOrFolderDesntExist));
+        assert_eq!(fs.free_clusters_count().unwrap(), free - 1);
+
+        // no stray long name entries are left behind
+        assert_eq!(fs.open_dir("/").unwrap().count(), 1);
+
+        assert_eq!(fs.remove_file("/dir").err(), Some(FsErr::DirEntryNotFile));
+        assert_eq!(fs.remove_file("/missing.txt").err(), Some(FsErr::FileOrFolderDesntExist));
+        fs.remove_dir("/dir").unwrap();
+        assert_eq!(fs.free_clusters_count().unwrap(), free);
+    }
+
+    #[test]
+    fn remove_dir_all_removes_tree() {
+        let disk = RamDisk::formatted(20000, None);
+        let fs = Fs::mount(&disk).unwrap();
+        let free = fs.free_clusters_count().unwrap();
+
+        fs.create_dir("/top").unwrap();
+        let mut path = String::from("/top");
+
+        // deeper than any stack would take with one frame per level
+        for depth in 0..200 {
+            write_file(&fs, &format!("{}/file with a long name {}.txt", path, depth), b"data");
+            fs.create_dir(&format!("{}/empty", path)).unwrap();
+            path.push_str("/sub");
+            fs.create_dir(&path).unwrap();
+        }
+
+        assert_eq!(fs.remove_dir("/top").err(), Some(FsErr::DirNotEmpty));
+        fs.remove_dir_all("/top").unwrap();
+        assert_eq!(fs.open_dir("/top").err(), Some(FsErr::FileOrFolderDesntExist));
+        assert_eq!(fs.free_clusters_count().unwrap(), free);
+    }
+
+    #[test]
+    fn remove_dir_all_rejects_loop() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        fs.create_dir("/a").unwrap();
+        fs.create_dir("/a/b").unwrap();
+        fs.create_dir("/a/b/c").unwrap();
+
+        let point_at = |path: &str, name: &str, target: &str| {
+            let target = fs.lookup(target).unwrap().unwrap();
+            let entry = fs.open_dir(path).unwrap().find_name(name).unwrap();
+            let mut data = *entry.data();
+            entry_set_first_cluster(&mut data, target.first_cluster());
+            fs.dir_entry_write(entry.dir_cluster(), entry.index(), &data).unwrap();
+        };
+
+        point_at("/a/b", "c", "/a/b");
+        assert_eq!(fs.remove_dir_all("/a").err(), Some(FsErr::FatTableError));
+
+        point_at("/a", "b", "/a");
+        assert_eq!(fs.remove_dir_all("/a").err(), Some(FsErr::FatTableError));
+    }
+
+    #[test]
+    fn rename_moves_entry() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/A.TXT", b"moved");
+        fs.create_dir("/dir").unwrap();
+
+        fs.rename("/A.TXT", "/dir/a longer name.txt").unwrap();
+        assert_eq!(read_file(&fs, "/dir/a longer name.txt").unwrap(), b"moved");
+        assert_eq!(read_file(&fs, "/A.TXT").err(), Some(FsErr::FileOrFolderDesntExist));
+
+        fs.rename("/dir", "/renamed").unwrap();
+        assert_eq!(read_file(&fs, "/renamed/a longer name.txt").unwrap(), b"moved");
+        assert_eq!(fs.rename("/renamed", "/renamed/inner").err(), Some(FsErr::MoveIntoSubdir));
+    }
+
+    #[test]
+    fn rename_keeps_existing_target() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/A.TXT", b"a");
+        write_file(&fs, "/B.TXT", b"b");
+
+        assert_eq!(fs.rename("/A.TXT", "/b.txt").err(), Some(FsErr::AlreadyExists));
+        assert_eq!(read_file(&fs, "/A.TXT").unwrap(), b"a");
+        assert_eq!(read_file(&fs, "/B.TXT").unwrap(), b"b");
+    }
+
+    #[test]
+    fn rename_replace() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/A.TXT", b"a");
+        write_file(&fs, "/B.TXT", b"b");
+        let free = fs.free_clusters_count().unwrap();
+
+        fs.rename_replace("/A.TXT", "/B.TXT").unwrap();
+        assert_eq!(read_file(&fs, "/B.TXT").unwrap(), b"a");
+        assert_eq!(read_file(&fs, "/A.TXT").err(), Some(FsErr::FileOrFolderDesntExist));
+        assert_eq!(fs.free_clusters_count().unwrap(), free + 1);
+
+        fs.create_dir("/dir").unwrap();
+        assert_eq!(fs.rename_replace("/B.TXT", "/dir").err(), Some(FsErr::AlreadyExists));
+    }
+
+    #[test]
+    fn rename_replace_full_root_keeps_target() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/A.TXT", b"a");
+        write_file(&fs, "/B.TXT", b"b");
+        fill_root(&fs);
+        let free = fs.free_clusters_count().unwrap();
+
+        // the lower case name needs a long name entry there is no room for
+        assert_eq!(fs.rename_replace("/A.TXT", "/b.txt").err(), Some(FsErr::RootDirFull));
+        assert_eq!(read_file(&fs, "/A.TXT").unwrap(), b"a");
+        assert_eq!(read_file(&fs, "/B.TXT").unwrap(), b"b");
+        assert_eq!(fs.free_clusters_count().unwrap(), free);
+    }
+
+    #[test]
+    fn rename_case_only() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/KEEP.TXT", b"keep");
+        fill_root(&fs);
+
+        assert_eq!(fs.rename("/KEEP.TXT", "/keep.txt").err(), Some(FsErr::RootDirFull));
+        assert_eq!(read_file(&fs, "/KEEP.TXT").unwrap(), b"keep");
+
+        fs.remove_file("/F0.TXT").unwrap();
+        fs.remove_file("/F1.TXT").unwrap();
+        fs.rename("/KEEP.TXT", "/keep.txt").unwrap();
+
+        let entry = fs.open_dir("/").unwrap().find_name("keep.txt").unwrap();
+        assert_eq!(entry.name(), "keep.txt");
+        assert_eq!(&entry.data()[..11], b"KEEP    TXT");
+        assert_eq!(read_file(&fs, "/keep.txt").unwrap(), b"keep");
+    }
+
+    #[test]
+    fn attributes() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        write_file(&fs, "/A.TXT", b"data");
+        fs.create_dir("/dir").unwrap();
+
+        let metadata = fs.metadata("/a.txt").unwrap();
+        assert_eq!(metadata.size, 4);
+        assert!(!metadata.is_dir());
+        assert!(fs.metadata("/dir").unwrap().is_dir());
+        assert!(fs.metadata("/").unwrap().is_dir());
+        assert_eq!(fs.metadata("/missing").err(), Some(FsErr::FileOrFolderDesntExist));
+
+        // only the changeable bits are taken, the others are kept
+        fs.set_attributes("/A.TXT", Attributes::HIDDEN | Attributes::SYSTEM | Attributes::DIRECTORY).unwrap();
+        fs.set_attributes("/dir", Attributes::READ_ONLY).unwrap();
+        fs.unmount().unwrap();
+
+        let fs = Fs::mount(&disk).unwrap();
+        assert_eq!(fs.metadata("/A.TXT").unwrap().attributes, Attributes::HIDDEN | Attributes::SYSTEM);
+        assert_eq!(fs.metadata("/dir").unwrap().attributes, Attributes::DIRECTORY | Attributes::READ_ONLY);
+        assert_eq!(read_file(&fs, "/A.TXT").unwrap(), b"data");
+
+        assert_eq!(fs.set_attributes("/", Attributes::HIDDEN).err(), Some(FsErr::InvalidName));
+        assert_eq!(fs.set_attributes("/dir/.", Attributes::HIDDEN).err(), Some(FsErr::InvalidName));
+        assert_eq!(fs.set_attributes("/missing", Attributes::HIDDEN).err(), Some(FsErr::FileOrFolderDesntExist));
+    }
+
+    fn boot_label(disk: &RamDisk, block: u32, fat32: bool) -> Vec<u8> {
+        let mut buff = [0u8; 512];
+        disk.read(block, &mut buff).unwrap();
+        let ext = ext_boot_offset(fat32);
+        buff[(ext + 7)..(ext + 18)].to_vec()
+    }
+
+    #[test]
+    fn set_label() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        assert_eq!(&fs.label().unwrap(), b"NO NAME    ");
+        write_file(&fs, "/A.TXT", b"a");
+
+        fs.set_label("my disk").unwrap();
+        fs.set_label("Data 1").unwrap();
+        assert_eq!(&fs.label().unwrap(), b"DATA 1     ");
+        fs.unmount().unwrap();
+
+        let fs = Fs::mount(&disk).unwrap();
+        assert_eq!(&fs.label().unwrap(), b"DATA 1     ");
+        assert_eq!(boot_label(&disk, 0, false), b"DATA 1     ");
+
+        // the label is no file
+        let names: Vec<String> = fs.open_dir("/").unwrap().map(|entry| entry.name().to_string()).collect();
+        assert_eq!(names, ["A.TXT"]);
+
+        for bad in ["twelve chars", " lead", "a*b", "\u{e9}t\u{e9}"].iter() {
+            assert_eq!(fs.set_label(bad).err(), Some(FsErr::InvalidName));
+        }
+        assert_eq!(&fs.label().unwrap(), b"DATA 1     ");
+
+        fs.set_label("").unwrap();
+        fs.flush().unwrap();
+        assert_eq!(&fs.label().unwrap(), b"NO NAME    ");
+        assert_eq!(boot_label(&disk, 0, false), b"NO NAME    ");
+        assert_eq!(fs.open_dir("/").unwrap().count(), 1);
+    }
+
+    #[test]
+    fn set_label_full_root() {
+        let disk = RamDisk::formatted(2880, None);
+        let fs = Fs::mount(&disk).unwrap();
+        fill_root(&fs);
+
+        assert_eq!(fs.set_label("FULL").err(), Some(FsErr::RootDirFull));
+        fs.flush().unwrap();
+        assert_eq!(&fs.label().unwrap(), b"NO NAME    ");
+        assert_eq!(boot_label(&disk, 0, false), b"NO NAME    ");
+    }
+
+    #[test]
+    fn set_label_fat32() {
+        let disk = RamDisk::formatted(600000, Some(FatType::Fat32));
+        let fs = Fs::mount(&disk).unwrap();
+        let backup = fs.bpb.backup_boot_sector;
+        assert_ne!(backup, 0);
+
+        fs.set_label("BIG").unwrap();
+        fs.unmount().unwrap();
+
+        let fs = Fs::mount(&disk).unwrap();
+        assert_eq!(&fs.label().unwrap(), b"BIG        ");
+        assert_eq!(boot_label(&disk, 0, true), b"BIG        ");
+        assert_eq!(boot_label(&disk, backup, true), b"BIG        ");
+        assert_eq!(fs.open_dir("/").unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,441 @@
+use core::convert::TryInto;
+
+use super::sector::{BlockDeviceIo, FsErr, BLOCK_MIN_SIZE, BLOCK_MAX_SIZE};
+use super::partition::Partition;
+use super::fs::u32_from_bytes;
+
+pub type Guid = [u8; 16];
+
+/// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7 in on-disk byte order.
+pub const MICROSOFT_BASIC_DATA_GUID: Guid = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44,
+    0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B in on-disk byte order.
+pub const EFI_SYSTEM_GUID: Guid = [
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11,
+    0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+];
+
+const HEADER_SIGNATURE: &[u8; 8] = b"EFI PART";
+const HEADER_MIN_SIZE: usize = 92;
+const ENTRY_MIN_SIZE: u32 = 128;
+// the usual array takes 16KiB, anything this large is garbage
+const ENTRIES_MAX_SIZE: u64 = 1024 * 1024;
+const PROTECTIVE_MBR_TYPE: u8 = 0xEE;
+
+fn u64_from_bytes(bytes: &[u8]) -> u64 {
+    u64::from(u32_from_bytes(bytes)) | (u64::from(u32_from_bytes(&bytes[4..])) << 32)
+}
+
+fn lba_from_bytes(bytes: &[u8]) -> Result<u32, FsErr> {
+    let lba = u64_from_bytes(bytes);
+
+    if lba > u64::from(u32::MAX) {
+        return Err(FsErr::PartitionOutOfStorageSpace);
+    }
+
+    Ok(lba as u32)
+}
+
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc;
+
+    for &byte in data {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    crc
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum GptPartitionKind {
+    MicrosoftBasicData,
+    EfiSystem,
+    Other,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct GptPartition {
+    /// Index in the partition entry array, counting from 1.
+    pub number: u32,
+    pub kind: GptPartitionKind,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_block: u32,
+    pub block_count: u32,
+    pub attributes: u64,
+}
+
+impl GptPartition {
+    /// Partitions that may hold a FAT file system.
+    pub fn is_fat(&self) -> bool {
+        self.kind != GptPartitionKind::Other
+    }
+
+    pub fn open<'bd>(&self, io: &'bd dyn BlockDeviceIo) -> Result<Partition<'bd>, FsErr> {
+        Partition::new(io, self.first_block, self.block_count)
+    }
+}
+
+pub struct Gpt<'bd> {
+    io: &'bd dyn BlockDeviceIo,
+    pub disk_guid: Guid,
+    pub first_usable_block: u32,
+    pub last_usable_block: u32,
+    /// Set when the primary header was damaged and the backup one is used.
+    pub backup: bool,
+
+    entries_first_block: u32,
+    entries_count: u32,
+    entry_size: u32,
+}
+
+impl <'bd> Gpt<'bd> {
+    pub fn new(io: &'bd dyn BlockDeviceIo) -> Result<Self, FsErr> {
+        let block_size = io.block_size() as usize;
+
+        if !(BLOCK_MIN_SIZE..=BLOCK_MAX_SIZE).contains(&block_size) {
+            return Err(FsErr::BadBlockSize);
+        }
+
+        let block_count = io.block_count();
+
+        if block_count < 3 {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        Self::check_protective_mbr(io)?;
+
+        match Self::read_header(io, 1) {
+            Ok(gpt) => Ok(gpt),
+            Err(FsErr::ReadError) | Err(FsErr::BadPartitionTable) => {
+                let mut gpt = Self::read_header(io, block_count - 1)?;
+                gpt.backup = true;
+                Ok(gpt)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_protective_mbr(io: &dyn BlockDeviceIo) -> Result<(), FsErr> {
+        let mut buff = [0u8; BLOCK_MAX_SIZE];
+        io.read(0, &mut buff[..io.block_size() as usize])?;
+
+        if buff[510] != 0x55 || buff[511] != 0xAA {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let protective = buff[446..510].chunks(16).any(|entry| {
+            entry[4] == PROTECTIVE_MBR_TYPE && u32_from_bytes(&entry[8..]) == 1
+        });
+
+        if !protective {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        Ok(())
+    }
+
+    fn read_header(io: &'bd dyn BlockDeviceIo, block: u32) -> Result<Self, FsErr> {
+        let block_size = io.block_size() as usize;
+        let mut buff = [0u8; BLOCK_MAX_SIZE];
+        io.read(block, &mut buff[..block_size])?;
+
+        if &buff[..8] != HEADER_SIGNATURE {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let header_size = u32_from_bytes(&buff[12..]) as usize;
+
+        if header_size < HEADER_MIN_SIZE || header_size > block_size {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        // header CRC is computed with its own field zeroed
+        let crc = u32_from_bytes(&buff[16..]);
+        buff[16..20].copy_from_slice(&[0; 4]);
+
+        if !crc32_update(!0, &buff[..header_size]) != crc {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        if lba_from_bytes(&buff[24..])? != block {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let entry_size = u32_from_bytes(&buff[84..]);
+
+        if entry_size < ENTRY_MIN_SIZE || !entry_size.is_power_of_two() {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let entries_count = u32_from_bytes(&buff[80..]);
+
+        if u64::from(entries_count) * u64::from(entry_size) > ENTRIES_MAX_SIZE {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let gpt = Self {
+            io,
+            disk_guid: buff[56..72].try_into().unwrap(),
+            first_usable_block: lba_from_bytes(&buff[40..])?,
+            last_usable_block: lba_from_bytes(&buff[48..])?,
+            backup: false,
+            entries_first_block: lba_from_bytes(&buff[72..])?,
+            entries_count,
+            entry_size,
+        };
+
+        if gpt.entries_crc()? != u32_from_bytes(&buff[88..]) {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        Ok(gpt)
+    }
+
+    fn entries_crc(&self) -> Result<u32, FsErr> {
+        let block_size = self.io.block_size();
+        let size = u64::from(self.entries_count) * u64::from(self.entry_size);
+        let blocks = size.div_ceil(u64::from(block_size));
+
+        if u64::from(self.entries_first_block) + blocks > u64::from(self.io.block_count()) {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let mut crc = !0;
+        let mut left = size as usize;
+        let mut buff = [0u8; BLOCK_MAX_SIZE];
+
+        for block in 0..(blocks as u32) {
+            self.io.read(self.entries_first_block + block, &mut buff[..block_size as usize])?;
+            let len = core::cmp::min(left, block_size as usize);
+            crc = crc32_update(crc, &buff[..len]);
+            left -= len;
+        }
+
+        Ok(!crc)
+    }
+
+    pub fn partitions(&self) -> GptIterator<'_, 'bd> {
+        GptIterator {
+            gpt: self,
+            index: 0,
+            cached_block: u32::MAX,
+            data: [0u8; BLOCK_MAX_SIZE],
+        }
+    }
+}
+
+/// Walks the used entries of the partition entry array.
+pub struct GptIterator<'gpt, 'bd: 'gpt> {
+    gpt: &'gpt Gpt<'bd>,
+    index: u32,
+    cached_block: u32,
+    data: [u8; BLOCK_MAX_SIZE],
+}
+
+impl <'gpt, 'bd: 'gpt> GptIterator<'gpt, 'bd> {
+    fn entry(&mut self, index: u32) -> Result<Option<GptPartition>, FsErr> {
+        let block_size = u64::from(self.gpt.io.block_size());
+        let position = u64::from(index) * u64::from(self.gpt.entry_size);
+        let block = self.gpt.entries_first_block + (position / block_size) as u32;
+        let offset = (position % block_size) as usize;
+
+        if block != self.cached_block {
+            self.cached_block = u32::MAX;
+            self.gpt.io.read(block, &mut self.data[..block_size as usize])?;
+            self.cached_block = block;
+        }
+
+        let entry = &self.data[offset..(offset + ENTRY_MIN_SIZE as usize)];
+        let type_guid: Guid = entry[..16].try_into().unwrap();
+
+        if type_guid == [0u8; 16] {
+            return Ok(None);
+        }
+
+        let first_block = lba_from_bytes(&entry[32..])?;
+        let last_block = lba_from_bytes(&entry[40..])?;
+
+        if last_block < first_block {
+            return Err(FsErr::BadPartitionTable);
+        }
+
+        let kind = if type_guid == MICROSOFT_BASIC_DATA_GUID {
+            GptPartitionKind::MicrosoftBasicData
+        } else if type_guid == EFI_SYSTEM_GUID {
+            GptPartitionKind::EfiSystem
+        } else {
+            GptPartitionKind::Other
+        };
+
+        Ok(Some(GptPartition {
+            number: index + 1,
+            kind,
+            type_guid,
+            unique_guid: entry[16..32].try_into().unwrap(),
+            first_block,
+            block_count: last_block - first_block + 1,
+            attributes: u64_from_bytes(&entry[48..]),
+        }))
+    }
+}
+
+impl <'gpt, 'bd: 'gpt> Iterator for GptIterator<'gpt, 'bd> {
+    type Item = Result<GptPartition, FsErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.gpt.entries_count {
+            let index = self.index;
+            self.index += 1;
+
+            match self.entry(index) {
+                Ok(Some(partition)) => return Some(Ok(partition)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.index = self.gpt.entries_count;
+                    return Some(Err(e));
+                },
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::ram::RamDisk;
+
+    const BLOCK_COUNT: u32 = 4096;
+    const ENTRIES_COUNT: u32 = 128;
+    const ENTRIES_BLOCKS: u32 = ENTRIES_COUNT * ENTRY_MIN_SIZE / 512;
+
+    fn read_blocks(disk: &RamDisk, first: u32, count: u32) -> Vec<u8> {
+        let mut data = vec![0u8; (count * 512) as usize];
+
+        for (i, block) in data.chunks_mut(512).enumerate() {
+            disk.read(first + i as u32, block).unwrap();
+        }
+
+        data
+    }
+
+    fn write_header(disk: &RamDisk, block: u32, alternate: u32, entries_block: u32, entries_count: u32) {
+        let entries_len = entries_count * ENTRY_MIN_SIZE;
+        let entries = read_blocks(disk, entries_block, entries_len.div_ceil(512));
+        let mut header = [0u8; 92];
+        header[..8].copy_from_slice(HEADER_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&u64::from(block).to_le_bytes());
+        header[32..40].copy_from_slice(&u64::from(alternate).to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&u64::from(BLOCK_COUNT - 34).to_le_bytes());
+        header[56..72].copy_from_slice(&[0x42; 16]);
+        header[72..80].copy_from_slice(&u64::from(entries_block).to_le_bytes());
+        header[80..84].copy_from_slice(&entries_count.to_le_bytes());
+        header[84..88].copy_from_slice(&ENTRY_MIN_SIZE.to_le_bytes());
+        header[88..92].copy_from_slice(&(!crc32_update(!0, &entries[..(entries_len as usize)])).to_le_bytes());
+        let crc = !crc32_update(!0, &header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.put(block, 0, &header);
+    }
+
+    /// Disk with a protective MBR, a FAT partition and an EFI system
+    /// partition, in both the primary and the backup array.
+    fn gpt_disk() -> RamDisk {
+        let disk = RamDisk::new(BLOCK_COUNT);
+        let mut mbr_entry = [0u8; 16];
+        mbr_entry[4] = PROTECTIVE_MBR_TYPE;
+        mbr_entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+        mbr_entry[12..16].copy_from_slice(&(BLOCK_COUNT - 1).to_le_bytes());
+        disk.put(0, 446, &mbr_entry);
+        disk.put(0, 510, &[0x55, 0xAA]);
+
+        let backup_entries = BLOCK_COUNT - 1 - ENTRIES_BLOCKS;
+
+        for &entries_block in [2, backup_entries].iter() {
+            for (slot, guid, first, last) in [(0usize, MICROSOFT_BASIC_DATA_GUID, 100u64, 1099u64), (2, EFI_SYSTEM_GUID, 2000, 2099)] {
+                let mut entry = [0u8; 128];
+                entry[..16].copy_from_slice(&guid);
+                entry[16..32].copy_from_slice(&[slot as u8 + 1; 16]);
+                entry[32..40].copy_from_slice(&first.to_le_bytes());
+                entry[40..48].copy_from_slice(&last.to_le_bytes());
+                disk.put(entries_block + slot as u32 / 4, (slot % 4) * 128, &entry);
+            }
+        }
+
+        write_header(&disk, 1, BLOCK_COUNT - 1, 2, ENTRIES_COUNT);
+        write_header(&disk, BLOCK_COUNT - 1, 1, backup_entries, ENTRIES_COUNT);
+        disk
+    }
+
+    fn partitions(gpt: &Gpt) -> Vec<(u32, GptPartitionKind, u32, u32)> {
+        gpt.partitions().map(|p| p.map(|p| (p.number, p.kind, p.first_block, p.block_count))).collect::<Result<_, _>>().unwrap()
+    }
+
+    const EXPECTED: [(u32, GptPartitionKind, u32, u32); 2] = [
+        (1, GptPartitionKind::MicrosoftBasicData, 100, 1000),
+        (3, GptPartitionKind::EfiSystem, 2000, 100),
+    ];
+
+    #[test]
+    fn reads_primary() {
+        let disk = gpt_disk();
+        let gpt = Gpt::new(&disk).unwrap();
+        assert!(!gpt.backup);
+        assert_eq!(gpt.disk_guid, [0x42; 16]);
+        assert_eq!(partitions(&gpt), EXPECTED);
+
+        let partition = gpt.partitions().next().unwrap().unwrap().open(&disk).unwrap();
+        assert_eq!(partition.block_count(), 1000);
+    }
+
+    #[test]
+    fn bad_header_crc_uses_backup() {
+        let disk = gpt_disk();
+        disk.put(1, 56, &[0x43]);
+
+        let gpt = Gpt::new(&disk).unwrap();
+        assert!(gpt.backup);
+        assert_eq!(partitions(&gpt), EXPECTED);
+    }
+
+    #[test]
+    fn bad_entries_crc_uses_backup() {
+        let disk = gpt_disk();
+        disk.put(2, 32, &[0x77]);
+
+        let gpt = Gpt::new(&disk).unwrap();
+        assert!(gpt.backup);
+        assert_eq!(partitions(&gpt), EXPECTED);
+
+        disk.put(BLOCK_COUNT - 1 - ENTRIES_BLOCKS, 32, &[0x77]);
+        assert_eq!(Gpt::new(&disk).err(), Some(FsErr::BadPartitionTable));
+    }
+
+    #[test]
+    fn rejects_huge_entry_array() {
+        let disk = gpt_disk();
+        // valid CRCs and the array fits on the disk, it is only too large
+        let entries_count = (ENTRIES_MAX_SIZE as u32) / ENTRY_MIN_SIZE + 1;
+        write_header(&disk, 1, BLOCK_COUNT - 1, 2, entries_count);
+        write_header(&disk, BLOCK_COUNT - 1, 1, 2, entries_count);
+        assert_eq!(Gpt::new(&disk).err(), Some(FsErr::BadPartitionTable));
+    }
+
+    #[test]
+    fn requires_protective_mbr() {
+        let disk = gpt_disk();
+        disk.put(0, 446 + 4, &[0x0C]);
+        assert_eq!(Gpt::new(&disk).err(), Some(FsErr::BadPartitionTable));
+    }
+}
@@ -0,0 +1,315 @@
+use super::sector::{BlockDeviceIo, FsErr, BLOCK_MAX_SIZE};
+use super::partition::Partition;
+use super::fs::u32_from_bytes;
+
+const PARTITION_TABLE_OFFSET: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+// guards against EBR chains that never end
+const EBRS_MAX: u32 = 128;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct MbrPartition {
+    /// 1..=4 for primary partitions, 5 and up for logical ones.
+    pub number: u32,
+    pub bootable: bool,
+    pub partition_type: u8,
+    pub first_block: u32,
+    pub block_count: u32,
+}
+
+impl MbrPartition {
+    fn parse(number: u32, entry: &[u8], base: u32) -> Result<Self, FsErr> {
+        let bootable = match entry[0] {
+            0x00 => false,
+            0x80 => true,
+            _ => return Err(FsErr::BadPartitionTable),
+        };
+
+        Ok(Self {
+            number,
+            bootable,
+            partition_type: entry[4],
+            first_block: base.wrapping_add(u32_from_bytes(&entry[8..])),
+            block_count: u32_from_bytes(&entry[12..]),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partition_type == 0 || self.block_count == 0
+    }
+
+    pub fn is_extended(&self) -> bool {
+        matches!(self.partition_type, 0x05 | 0x0F | 0x85)
+    }
+
+    pub fn is_fat(&self) -> bool {
+        matches!(self.partition_type, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E)
+    }
+
+    pub fn open<'bd>(&self, io: &'bd dyn BlockDeviceIo) -> Result<Partition<'bd>, FsErr> {
+        Partition::new(io, self.first_block, self.block_count)
+    }
+}
+
+fn read_table(io: &dyn BlockDeviceIo, block: u32, table: &mut [u8; 4 * PARTITION_ENTRY_SIZE]) -> Result<(), FsErr> {
+    let block_size = io.block_size() as usize;
+
+    if block_size > BLOCK_MAX_SIZE {
+        return Err(FsErr::BadBlockSize);
+    }
+
+    if block >= io.block_count() {
+        return Err(FsErr::PartitionOutOfStorageSpace);
+    }
+
+    let mut buff = [0u8; BLOCK_MAX_SIZE];
+    io.read(block, &mut buff[..block_size])?;
+
+    if buff[510] != 0x55 || buff[511] != 0xAA {
+        return Err(FsErr::BadPartitionTable);
+    }
+
+    let len = table.len();
+    table.copy_from_slice(&buff[PARTITION_TABLE_OFFSET..(PARTITION_TABLE_OFFSET + len)]);
+    Ok(())
+}
+
+/// Walks the primary partitions of the MBR, then the logical partitions of
+/// the extended partition through its chain of EBRs. Empty entries and the
+/// extended partition itself are skipped.
+pub struct MbrIterator<'bd> {
+    io: &'bd dyn BlockDeviceIo,
+    table: [u8; 4 * PARTITION_ENTRY_SIZE],
+    index: usize,
+
+    extended_first_block: u32,
+    ebr: u32,
+    ebr_count: u32,
+    logical_number: u32,
+    failed: bool,
+}
+
+impl <'bd> MbrIterator<'bd> {
+    pub fn new(io: &'bd dyn BlockDeviceIo) -> Result<Self, FsErr> {
+        let mut table = [0u8; 4 * PARTITION_ENTRY_SIZE];
+        read_table(io, 0, &mut table)?;
+
+        // validate every entry up front so a volume without a partition table
+        // is rejected here and not half way through the iteration
+        for (i, entry) in table.chunks(PARTITION_ENTRY_SIZE).enumerate() {
+            MbrPartition::parse(i as u32 + 1, entry, 0)?;
+        }
+
+        Ok(Self {
+            io,
+            table,
+            index: 0,
+            extended_first_block: 0,
+            ebr: 0,
+            ebr_count: 0,
+            logical_number: 5,
+            failed: false,
+        })
+    }
+
+    fn next_primary(&mut self) -> Result<Option<MbrPartition>, FsErr> {
+        while self.index < 4 {
+            let offset = self.index * PARTITION_ENTRY_SIZE;
+            let entry = &self.table[offset..(offset + PARTITION_ENTRY_SIZE)];
+            self.index += 1;
+
+            let partition = MbrPartition::parse(self.index as u32, entry, 0)?;
+
+            if partition.is_empty() {
+                continue;
+            }
+
+            if partition.is_extended() {
+                if self.extended_first_block == 0 {
+                    self.extended_first_block = partition.first_block;
+                    self.ebr = partition.first_block;
+                }
+                continue;
+            }
+
+            return Ok(Some(partition));
+        }
+
+        Ok(None)
+    }
+
+    fn next_logical(&mut self) -> Result<Option<MbrPartition>, FsErr> {
+        while self.ebr != 0 {
+            // empty entries count too, an EBR can link on without a partition
+            if self.ebr_count >= EBRS_MAX {
+                return Err(FsErr::BadPartitionTable);
+            }
+            self.ebr_count += 1;
+
+            let mut table = [0u8; 4 * PARTITION_ENTRY_SIZE];
+            read_table(self.io, self.ebr, &mut table)?;
+
+            // logical partition start is relative to its EBR
+            let logical = MbrPartition::parse(self.logical_number, &table[..PARTITION_ENTRY_SIZE], self.ebr)?;
+            // next EBR is relative to the start of the extended partition
+            let next = MbrPartition::parse(0, &table[PARTITION_ENTRY_SIZE..], self.extended_first_block)?;
+
+            self.ebr = if next.is_empty() {
+                0
+            } else if next.first_block <= self.ebr {
+                // EBRs follow each other on the disk, a link to itself or
+                // back would loop
+                return Err(FsErr::BadPartitionTable);
+            } else {
+                next.first_block
+            };
+
+            if !logical.is_empty() {
+                self.logical_number += 1;
+                return Ok(Some(logical));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl <'bd> Iterator for MbrIterator<'bd> {
+    type Item = Result<MbrPartition, FsErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let next = match self.next_primary() {
+            Ok(None) => self.next_logical(),
+            other => other,
+        };
+
+        match next {
+            Ok(partition) => partition.map(Ok),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fs::Fs;
+    use crate::fs::format::{format, FormatOptions};
+    use crate::fs::ram::RamDisk;
+
+    fn entry(partition_type: u8, first_block: u32, block_count: u32) -> [u8; 16] {
+        let mut entry = [0u8; 16];
+        entry[4] = partition_type;
+        entry[8..12].copy_from_slice(&first_block.to_le_bytes());
+        entry[12..16].copy_from_slice(&block_count.to_le_bytes());
+        entry
+    }
+
+    fn put_table(disk: &RamDisk, block: u32, entries: &[[u8; 16]]) {
+        for (i, entry) in entries.iter().enumerate() {
+            disk.put(block, PARTITION_TABLE_OFFSET + i * PARTITION_ENTRY_SIZE, entry);
+        }
+
+        disk.put(block, 510, &[0x55, 0xAA]);
+    }
+
+    /// Two primary partitions and an extended one at 1000 holding a chain
+    /// of three EBRs, the second one without a partition of its own.
+    fn mbr_disk() -> RamDisk {
+        let disk = RamDisk::new(8192);
+        put_table(&disk, 0, &[entry(0x0C, 64, 100), [0; 16], entry(0x05, 1000, 4000), entry(0x83, 200, 50)]);
+        put_table(&disk, 1000, &[entry(0x06, 8, 500), entry(0x05, 1000, 10)]);
+        put_table(&disk, 2000, &[[0; 16], entry(0x05, 3000, 10)]);
+        put_table(&disk, 4000, &[entry(0x0B, 16, 300)]);
+        disk
+    }
+
+    fn partitions(disk: &RamDisk) -> Vec<Result<(u32, u8, u32, u32), FsErr>> {
+        MbrIterator::new(disk).unwrap().map(|p| p.map(|p| (p.number, p.partition_type, p.first_block, p.block_count))).collect()
+    }
+
+    #[test]
+    fn walks_primary_and_logical() {
+        assert_eq!(partitions(&mbr_disk()), [
+            Ok((1, 0x0C, 64, 100)),
+            Ok((4, 0x83, 200, 50)),
+            Ok((5, 0x06, 1008, 500)),
+            Ok((6, 0x0B, 4016, 300)),
+        ]);
+    }
+
+    #[test]
+    fn rejects_looping_ebrs() {
+        // the empty EBR links to itself
+        let disk = mbr_disk();
+        put_table(&disk, 2000, &[[0; 16], entry(0x05, 1000, 10)]);
+        assert_eq!(partitions(&disk).last(), Some(&Err(FsErr::BadPartitionTable)));
+
+        // and back to the first one
+        put_table(&disk, 2000, &[[0; 16], entry(0x05, 0, 10)]);
+        assert_eq!(partitions(&disk).last(), Some(&Err(FsErr::BadPartitionTable)));
+    }
+
+    #[test]
+    fn rejects_endless_ebr_chain() {
+        let disk = RamDisk::new(8192);
+        put_table(&disk, 0, &[entry(0x05, 100, 8000)]);
+
+        for i in 0..(EBRS_MAX + 1) {
+            put_table(&disk, 100 + i, &[[0; 16], entry(0x05, i + 1, 1)]);
+        }
+
+        assert_eq!(partitions(&disk), [Err(FsErr::BadPartitionTable)]);
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        let disk = RamDisk::new(64);
+        assert_eq!(MbrIterator::new(&disk).err(), Some(FsErr::BadPartitionTable));
+
+        put_table(&disk, 0, &[entry(0x0C, 1, 10)]);
+        disk.put(0, PARTITION_TABLE_OFFSET, &[0x7F]);
+        assert_eq!(MbrIterator::new(&disk).err(), Some(FsErr::BadPartitionTable));
+    }
+
+    #[test]
+    fn partition_bounds() {
+        let disk = RamDisk::new(1000);
+        assert_eq!(Partition::new(&disk, 900, 101).err(), Some(FsErr::PartitionOutOfStorageSpace));
+        assert_eq!(Partition::new(&disk, u32::MAX, 2).err(), Some(FsErr::PartitionOutOfStorageSpace));
+
+        let partition = Partition::new(&disk, 900, 100).unwrap();
+        let mut buff = [0u8; 512];
+        assert_eq!(partition.read(100, &mut buff), Err(FsErr::OutOfRange));
+        assert_eq!(partition.write(100, &buff), Err(FsErr::OutOfRange));
+
+        partition.write(99, &[0xAB; 512]).unwrap();
+        disk.read(999, &mut buff).unwrap();
+        assert_eq!(buff, [0xAB; 512]);
+    }
+
+    #[test]
+    fn mounts_partition() {
+        let disk = mbr_disk();
+        let logical = MbrIterator::new(&disk).unwrap().nth(2).unwrap().unwrap();
+        let partition = logical.open(&disk).unwrap();
+        format(&partition, FormatOptions::default()).unwrap();
+
+        let fs = Fs::mount(&partition).unwrap();
+        fs.create("/A.TXT").unwrap().close().unwrap();
+        fs.unmount().unwrap();
+
+        // the boot sector landed at the start of the partition
+        let mut buff = [0u8; 512];
+        disk.read(logical.first_block, &mut buff).unwrap();
+        assert_eq!(&buff[510..], &[0x55, 0xAA]);
+        assert!(Fs::mount(&disk).is_err());
+    }
+}
@@ -1,5 +1,13 @@
+#[allow(clippy::module_inception)]
 pub mod fs;
 pub mod sector;
 pub mod file;
 pub mod dir;
-pub mod stream;
\ No newline at end of file
+pub mod stream;
+pub mod partition;
+pub mod mbr;
+pub mod gpt;
+pub mod format;
+pub mod time;
+#[cfg(test)]
+mod ram;
@@ -0,0 +1,48 @@
+use super::sector::{BlockDeviceIo, FsErr};
+
+/// Window of `block_count` blocks starting at `first_block` of the underlying
+/// device, so a file system inside a partition can be mounted directly.
+pub struct Partition<'bd> {
+    io: &'bd dyn BlockDeviceIo,
+    first_block: u32,
+    block_count: u32,
+}
+
+impl <'bd> Partition<'bd> {
+    pub fn new(io: &'bd dyn BlockDeviceIo, first_block: u32, block_count: u32) -> Result<Self, FsErr> {
+        match first_block.checked_add(block_count) {
+            Some(end) if end <= io.block_count() => Ok(Self { io, first_block, block_count }),
+            _ => Err(FsErr::PartitionOutOfStorageSpace),
+        }
+    }
+
+    pub fn first_block(&self) -> u32 {
+        self.first_block
+    }
+}
+
+impl <'bd> BlockDeviceIo for Partition<'bd> {
+    fn block_size(&self) -> u32 {
+        self.io.block_size()
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), FsErr> {
+        if block >= self.block_count {
+            return Err(FsErr::OutOfRange);
+        }
+
+        self.io.read(self.first_block + block, data)
+    }
+
+    fn write(&self, block: u32, data: &[u8]) -> Result<(), FsErr> {
+        if block >= self.block_count {
+            return Err(FsErr::OutOfRange);
+        }
+
+        self.io.write(self.first_block + block, data)
+    }
+}
@@ -0,0 +1,64 @@
+use core::cell::RefCell;
+use super::sector::{BlockDeviceIo, FsErr};
+use super::format::{format, FormatOptions};
+use super::fs::FatType;
+
+/// Block device kept in memory, for the tests.
+pub struct RamDisk {
+    data: RefCell<Vec<u8>>,
+    block_size: u32,
+}
+
+impl RamDisk {
+    pub fn new(block_count: u32) -> Self {
+        Self {
+            data: RefCell::new(vec![0; block_count as usize * 512]),
+            block_size: 512,
+        }
+    }
+
+    /// Disk of `block_count` blocks formatted with the default options.
+    pub fn formatted(block_count: u32, fat_type: Option<FatType>) -> Self {
+        let disk = Self::new(block_count);
+        format(&disk, FormatOptions { fat_type, ..Default::default() }).unwrap();
+        disk
+    }
+
+    /// Overwrites part of a block.
+    pub fn put(&self, block: u32, offset: usize, bytes: &[u8]) {
+        let range = self.range(block, self.block_size as usize).unwrap();
+        self.data.borrow_mut()[range][offset..(offset + bytes.len())].copy_from_slice(bytes);
+    }
+
+    fn range(&self, block: u32, len: usize) -> Result<core::ops::Range<usize>, FsErr> {
+        let start = block as usize * self.block_size as usize;
+
+        if start + len > self.data.borrow().len() {
+            return Err(FsErr::OutOfRange);
+        }
+
+        Ok(start..(start + len))
+    }
+}
+
+impl BlockDeviceIo for RamDisk {
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.data.borrow().len() / self.block_size as usize) as u32
+    }
+
+    fn read(&self, block: u32, data: &mut [u8]) -> Result<(), FsErr> {
+        let range = self.range(block, data.len())?;
+        data.copy_from_slice(&self.data.borrow()[range]);
+        Ok(())
+    }
+
+    fn write(&self, block: u32, data: &[u8]) -> Result<(), FsErr> {
+        let range = self.range(block, data.len())?;
+        self.data.borrow_mut()[range].copy_from_slice(data);
+        Ok(())
+    }
+}
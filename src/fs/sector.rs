@@ -9,6 +9,7 @@ pub enum FsErr {
     WriteError,
     OutOfRange,
     DirEntryNotFile,
+    DirEntryNotDir,
     FileOrFolderDesntExist,
     FatTableError,
     NoFreeCluster,
@@ -17,10 +18,23 @@ pub enum FsErr {
     EndOfFile,
     UnExpectedEndOfFile,
     EndOfStream,
+    BadBootSignature,
+    BadJumpInstruction,
+    BadBpb,
+    RootDirFull,
+    BadPartitionTable,
+    BadFormatOptions,
+    InvalidName,
+    DirFull,
+    AlreadyExists,
+    DirNotEmpty,
+    MoveIntoSubdir,
+    BadOpenOptions,
+    AccessDenied,
 }
 
-const BLOCK_MAX_SIZE: usize = 4096;
-const BLOCK_MIN_SIZE: usize = 512;
+pub const BLOCK_MAX_SIZE: usize = 4096;
+pub const BLOCK_MIN_SIZE: usize = 512;
 
 pub trait BlockDeviceIo {
     fn block_size(&self) -> u32;
@@ -29,14 +43,25 @@ pub trait BlockDeviceIo {
     fn write(&self, block: u32, data: &[u8]) -> Result<(), FsErr>;
 }
 
-pub struct BlockDeviceCache<'bd> {
+/// Blocks kept in memory by `Sector` unless a different capacity is given.
+pub const CACHE_BLOCKS: usize = 4;
+
+#[derive(Copy, Clone)]
+struct CachedBlock {
+    number: u32,
+    dirty: bool,
+    last_used: u64,
+    data: [u8; BLOCK_MAX_SIZE],
+}
+
+/// Write-back cache of the `N` most recently used blocks.
+pub struct BlockDeviceCache<'bd, const N: usize = CACHE_BLOCKS> {
     io: &'bd dyn BlockDeviceIo,
     block_size: usize,
     block_count: u32,
 
-    cached_block: u32,
-    data: [u8; BLOCK_MAX_SIZE],
-    dirty: bool,
+    blocks: [CachedBlock; N],
+    clock: u64,
 }
 
 pub trait BlockDevice {
@@ -46,62 +71,91 @@ pub trait BlockDevice {
     fn flush(&mut self) -> Result<(), FsErr>;
 }
 
-impl <'bd> BlockDevice for BlockDeviceCache <'bd> {
+impl <'bd, const N: usize> BlockDevice for BlockDeviceCache <'bd, N> {
     fn block_size(&self) -> u32 {
         self.block_size as u32
     }
 
     fn get(&mut self, number: u32) -> Result<&[u8], FsErr> {
-        self.sync(number)?;
-        Ok(&self.data[..self.block_size])
+        let index = self.sync(number)?;
+        Ok(&self.blocks[index].data[..self.block_size])
     }
     
     fn get_mut(&mut self, number: u32) -> Result<&mut [u8], FsErr> {
-        self.sync(number)?;
-        self.dirty = true;
-        Ok(&mut self.data[..self.block_size])
+        let index = self.sync(number)?;
+        let block = &mut self.blocks[index];
+        block.dirty = true;
+        Ok(&mut block.data[..self.block_size])
     }
 
     fn flush(&mut self) -> Result<(), FsErr> {
-        if self.dirty {
-            self.io.write(self.cached_block, &self.data[..self.block_size])?;
-            self.dirty = false;
+        for index in 0..N {
+            self.write_back(index)?;
         }
         Ok(())
     }
 }
 
-impl <'bd> BlockDeviceCache <'bd> {
+impl <'bd, const N: usize> BlockDeviceCache <'bd, N> {
     pub fn new(io: &'bd dyn BlockDeviceIo) -> Self {
+        assert_ne!(N, 0);
+
         Self {
             io,
             block_size: io.block_size() as usize,
             block_count: io.block_count(),
-            cached_block: core::u32::MAX,
-            data: [0u8; BLOCK_MAX_SIZE],
-            dirty: false,
+            blocks: [CachedBlock {
+                number: u32::MAX,
+                dirty: false,
+                last_used: 0,
+                data: [0u8; BLOCK_MAX_SIZE],
+            }; N],
+            clock: 0,
         }
     }
 
-    fn sync(&mut self, number: u32) -> Result<(), FsErr> {
-        if number != self.cached_block {
-            if number >= self.block_count {
-                return Err(FsErr::OutOfRange);
-            }
+    fn write_back(&mut self, index: usize) -> Result<(), FsErr> {
+        let block = &mut self.blocks[index];
 
-            self.flush()?;
-            self.io.read(number, &mut self.data[..self.block_size])?;
-            self.cached_block = number;
+        if block.dirty {
+            self.io.write(block.number, &block.data[..self.block_size])?;
+            block.dirty = false;
         }
         Ok(())
     }
+
+    fn sync(&mut self, number: u32) -> Result<usize, FsErr> {
+        if number >= self.block_count {
+            return Err(FsErr::OutOfRange);
+        }
+
+        self.clock += 1;
+
+        if let Some(index) = self.blocks.iter().position(|block| block.number == number) {
+            self.blocks[index].last_used = self.clock;
+            return Ok(index);
+        }
+
+        // never used slots have the oldest stamp and go first
+        let index = (0..N).min_by_key(|&index| self.blocks[index].last_used).unwrap();
+        self.write_back(index)?;
+
+        let block = &mut self.blocks[index];
+        // a failed read leaves the buffer half overwritten
+        block.number = u32::MAX;
+        block.last_used = 0;
+        self.io.read(number, &mut block.data[..self.block_size])?;
+        block.number = number;
+        block.last_used = self.clock;
+        Ok(index)
+    }
 }
 
-pub struct Sector<'bd> {
-    sector: RefCell<BlockDeviceCache<'bd>>,
+pub struct Sector<'bd, const N: usize = CACHE_BLOCKS> {
+    sector: RefCell<BlockDeviceCache<'bd, N>>,
 }
 
-impl <'bd> Sector<'bd> {
+impl <'bd, const N: usize> Sector<'bd, N> {
     pub fn new(io: &'bd dyn BlockDeviceIo) -> Self {
         Self { sector: RefCell::new(BlockDeviceCache::new(io)) }
     }
@@ -126,4 +180,144 @@ impl <'bd> Sector<'bd> {
         let mut s = self.sector.borrow_mut();
         s.flush()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use crate::fs::fs::Fs;
+    use crate::fs::ram::RamDisk;
+
+    /// Counts the reads and writes reaching the disk.
+    struct Counting {
+        disk: RamDisk,
+        reads: Cell<u32>,
+        writes: Cell<u32>,
+    }
+
+    impl Counting {
+        fn new(block_count: u32) -> Self {
+            Self { disk: RamDisk::new(block_count), reads: Cell::new(0), writes: Cell::new(0) }
+        }
+
+        fn block(&self, block: u32) -> Vec<u8> {
+            let mut buff = vec![0u8; 512];
+            self.disk.read(block, &mut buff).unwrap();
+            buff
+        }
+    }
+
+    impl BlockDeviceIo for Counting {
+        fn block_size(&self) -> u32 {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> u32 {
+            self.disk.block_count()
+        }
+
+        fn read(&self, block: u32, data: &mut [u8]) -> Result<(), FsErr> {
+            self.reads.set(self.reads.get() + 1);
+            self.disk.read(block, data)
+        }
+
+        fn write(&self, block: u32, data: &[u8]) -> Result<(), FsErr> {
+            self.writes.set(self.writes.get() + 1);
+            self.disk.write(block, data)
+        }
+    }
+
+    #[test]
+    fn writes_back_on_flush() {
+        let disk = Counting::new(16);
+        let sector = Sector::<4>::new(&disk);
+
+        sector.write(3, 10, &[1, 2, 3]).unwrap();
+        sector.write(3, 13, &[4]).unwrap();
+        assert_eq!(disk.writes.get(), 0);
+        assert_eq!(disk.block(3)[10..14], [0; 4]);
+
+        sector.flush().unwrap();
+        assert_eq!(disk.writes.get(), 1);
+        assert_eq!(disk.block(3)[10..14], [1, 2, 3, 4]);
+
+        // clean blocks are not written again
+        sector.flush().unwrap();
+        assert_eq!(disk.writes.get(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let disk = Counting::new(16);
+        let sector = Sector::<2>::new(&disk);
+        let mut buff = [0u8; 1];
+
+        sector.read(0, 0, &mut buff).unwrap();
+        sector.read(1, 0, &mut buff).unwrap();
+        sector.read(0, 0, &mut buff).unwrap();
+        assert_eq!(disk.reads.get(), 2);
+
+        // 1 is the least recently used and makes room for 2
+        sector.read(2, 0, &mut buff).unwrap();
+        sector.read(0, 0, &mut buff).unwrap();
+        assert_eq!(disk.reads.get(), 3);
+
+        sector.read(1, 0, &mut buff).unwrap();
+        assert_eq!(disk.reads.get(), 4);
+    }
+
+    #[test]
+    fn writes_back_on_eviction() {
+        let disk = Counting::new(16);
+        let sector = Sector::<1>::new(&disk);
+        let mut buff = [0u8; 2];
+
+        sector.write(5, 0, &[7, 8]).unwrap();
+        sector.read(6, 0, &mut buff).unwrap();
+        assert_eq!(disk.writes.get(), 1);
+        assert_eq!(disk.block(5)[..2], [7, 8]);
+
+        // and reads it back from the disk
+        sector.read(5, 0, &mut buff).unwrap();
+        assert_eq!(buff, [7, 8]);
+        assert_eq!(disk.reads.get(), 3);
+    }
+
+    #[test]
+    fn rejects_blocks_past_the_end() {
+        let disk = Counting::new(16);
+        let sector = Sector::<2>::new(&disk);
+
+        assert_eq!(sector.read(16, 0, &mut [0u8; 1]), Err(FsErr::OutOfRange));
+        assert_eq!(sector.write(16, 0, &[0u8; 1]), Err(FsErr::OutOfRange));
+        assert_eq!(disk.reads.get(), 0);
+    }
+
+    fn round_trip<const N: usize>() {
+        let disk = RamDisk::formatted(8192, None);
+        let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+
+        let fs = Fs::<N>::mount_with_cache(&disk).unwrap();
+        let mut file = fs.create("/DATA.BIN").unwrap();
+        file.write(&data).unwrap();
+        file.close().unwrap();
+        fs.unmount().unwrap();
+
+        let fs = Fs::mount(&disk).unwrap();
+        let mut file = fs.open("/DATA.BIN").unwrap();
+        let mut read = vec![0u8; data.len()];
+        let mut len = 0;
+
+        while len < read.len() {
+            len += file.read(&mut read[len..]).unwrap();
+        }
+
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn cache_sizes() {
+        round_trip::<1>();
+        round_trip::<16>();
+    }
+}
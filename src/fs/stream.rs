@@ -1,5 +1,6 @@
-use super::fs::{Fs, ClusterValue};
-use super::sector::FsErr;
+use super::fs::{Fs, FatType, ClusterValue};
+use super::sector::{FsErr, CACHE_BLOCKS};
+use core::convert::TryFrom;
 
 pub enum SeekFrom {
     Start(u32),
@@ -20,8 +21,15 @@ pub trait Seek {
     fn seek(&mut self, pos: SeekFrom) -> Result<u32, FsErr>;
 }
 
-pub struct Stream<'stream, 'bd: 'stream> {
-    fs: &'stream Fs<'bd>,
+enum Region {
+    Chain,
+    // FAT12/16 root directory, a run of sectors outside of the data area
+    Fixed { first_sector: u32, size: u32 },
+}
+
+pub struct Stream<'stream, 'bd: 'stream, const N: usize = CACHE_BLOCKS> {
+    fs: &'stream Fs<'bd, N>,
+    region: Region,
     first_cluster: u32,
 
     cluster: u32,
@@ -30,12 +38,92 @@ pub struct Stream<'stream, 'bd: 'stream> {
     global_offset: u32,
 }
 
-impl <'stream, 'bd: 'stream> Stream<'stream, 'bd> {
+impl <'stream, 'bd: 'stream, const N: usize> Stream<'stream, 'bd, N> {
+    pub fn new(fs: &'stream Fs<'bd, N>, first_cluster: u32) -> Self {
+        Self {
+            fs,
+            region: Region::Chain,
+            first_cluster,
+            cluster: first_cluster,
+            sector: 0,
+            offset: 0,
+            global_offset: 0,
+        }
+    }
+
+    /// Root directory stream, the fixed region on FAT12/16 or the root
+    /// cluster chain on FAT32.
+    pub fn root(fs: &'stream Fs<'bd, N>) -> Self {
+        match fs.fat_type() {
+            FatType::Fat32 => Self::new(fs, fs.bpb.root_cluster),
+            FatType::Fat16 | FatType::Fat12 => Self {
+                fs,
+                region: Region::Fixed {
+                    first_sector: fs.bpb.root_dir_first_sector(),
+                    size: fs.bpb.root_entry_count * 32,
+                },
+                first_cluster: 0,
+                cluster: 0,
+                sector: 0,
+                offset: 0,
+                global_offset: 0,
+            },
+        }
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+
+    /// Size of the region, the whole chain for a cluster chain.
+    pub fn region_len(&self) -> Result<u32, FsErr> {
+        match self.region {
+            Region::Fixed { size, .. } => Ok(size),
+            Region::Chain if self.first_cluster == 0 => Ok(0),
+            Region::Chain => {
+                let mut cluster = self.first_cluster;
+                let mut count = 1u32;
+
+                loop {
+                    match self.fs.table_get(cluster)? {
+                        ClusterValue::Next(next) => cluster = next,
+                        ClusterValue::Last => break,
+                        ClusterValue::Free | ClusterValue::Bad => return Err(FsErr::FatTableError),
+                    }
+
+                    count += 1;
+                }
+
+                count.checked_mul(self.fs.cluster_size).ok_or(FsErr::OutOfRange)
+            },
+        }
+    }
+
+    pub fn position(&self) -> u32 {
+        self.global_offset
+    }
+
+    pub(crate) fn fs(&self) -> &'stream Fs<'bd, N> {
+        self.fs
+    }
+
     fn go_to_next_sector_if_necessary(&mut self) -> Result<(), FsErr> {
+        if let Region::Fixed { size, .. } = self.region {
+            if self.global_offset >= size {
+                return Err(FsErr::EndOfStream);
+            }
+        }
+
         if (self.offset as u32) < self.fs.sector_size {
             return Ok(());
         }
 
+        if let Region::Fixed { .. } = self.region {
+            self.sector += 1;
+            self.offset = 0;
+            return Ok(());
+        }
+
         if self.sector + 1 < self.fs.sectors_in_cluster {
             self.sector += 1;
             self.offset = 0;
@@ -47,25 +135,63 @@ impl <'stream, 'bd: 'stream> Stream<'stream, 'bd> {
                 self.cluster = next;
                 self.sector = 0;
                 self.offset = 0;
-                return Ok(());
+                Ok(())
             }
 
-            ClusterValue::Last => return Err(FsErr::EndOfStream),
-            ClusterValue::Bad | ClusterValue::Free => return Err(FsErr::FatTableError),
+            ClusterValue::Last => Err(FsErr::EndOfStream),
+            ClusterValue::Bad | ClusterValue::Free => Err(FsErr::FatTableError),
         }
     }
 
+    fn current_sector(&self) -> Result<u32, FsErr> {
+        match self.region {
+            Region::Chain => Ok(self.fs.cluster_to_sector(self.cluster)? + self.sector),
+            Region::Fixed { first_sector, .. } => Ok(first_sector + self.sector),
+        }
+    }
+
+    fn current_len(&self, len: usize) -> usize {
+        let len = core::cmp::min(len, (self.fs.sector_size as usize) - self.offset);
+
+        match self.region {
+            Region::Chain => len,
+            Region::Fixed { size, .. } => core::cmp::min(len, (size - self.global_offset) as usize),
+        }
+    }
+
+    /// Cuts or grows the chain to `cluster_count` clusters, the stream is
+    /// rewound to its start.
     pub fn set_len(&mut self, cluster_count: u32) -> Result<(), FsErr> {
-        self.fs.table_chain_set_len(self.first_cluster, cluster_count)
+        if let Region::Fixed { .. } = self.region {
+            return Err(FsErr::OutOfRange);
+        }
+
+        if self.first_cluster == 0 {
+            if cluster_count != 0 {
+                self.first_cluster = self.fs.table_chain_create(cluster_count)?;
+            }
+        } else {
+            self.fs.table_chain_set_len(self.first_cluster, cluster_count)?;
+
+            if cluster_count == 0 {
+                self.first_cluster = 0;
+            }
+        }
+
+        self.cluster = self.first_cluster;
+        self.sector = 0;
+        self.offset = 0;
+        self.global_offset = 0;
+        Ok(())
     }
 }
 
-impl <'stream, 'bd> Read for Stream<'stream, 'bd> {
+impl <'stream, 'bd, const N: usize> Read for Stream<'stream, 'bd, N> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsErr> {
         self.go_to_next_sector_if_necessary()?;
 
-        let len = core::cmp::min(buf.len(), (self.fs.sector_size as usize) - self.offset);
-        let sector = self.fs.cluster_to_sector(self.cluster) + self.sector;
+        let len = self.current_len(buf.len());
+        let sector = self.current_sector()?;
         self.fs.sector.read(sector, self.offset, &mut buf[..len])?;
         self.offset += len;
         self.global_offset += len as u32;
@@ -73,9 +199,20 @@ impl <'stream, 'bd> Read for Stream<'stream, 'bd> {
     }
 }
 
-impl <'stream, 'bd> Write for Stream<'stream, 'bd> {
+impl <'stream, 'bd, const N: usize> Write for Stream<'stream, 'bd, N> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, FsErr> {
+        // empty files have no cluster until the first write
+        if let Region::Chain = self.region {
+            if self.first_cluster == 0 {
+                self.first_cluster = self.fs.table_chain_create(1)?;
+                self.cluster = self.first_cluster;
+            }
+        }
+
         match self.go_to_next_sector_if_necessary() {
+            Err(FsErr::EndOfStream) if matches!(self.region, Region::Fixed { .. }) => {
+                return Err(FsErr::RootDirFull);
+            },
             Err(FsErr::EndOfStream) => {
                 let next = self.fs.table_chain_extend(self.cluster, 1)?;
                 self.cluster = next;
@@ -86,8 +223,8 @@ impl <'stream, 'bd> Write for Stream<'stream, 'bd> {
             Err(e) => return Err(e),
             _ => {},
         }
-        let len = core::cmp::min(buf.len(), (self.fs.sector_size as usize) - self.offset);
-        let sector = self.fs.cluster_to_sector(self.cluster) + self.sector;
+        let len = self.current_len(buf.len());
+        let sector = self.current_sector()?;
         self.fs.sector.write(sector, self.offset, &buf[..len])?;
         self.offset += len;
         self.global_offset += len as u32;
@@ -95,28 +232,58 @@ impl <'stream, 'bd> Write for Stream<'stream, 'bd> {
     }
 
     fn flush(&mut self) -> Result<(), FsErr> {
-        self.fs.sector.flush()
+        self.fs.flush()
+    }
+}
+
+/// `base` moved by `offset`, which must not go below 0.
+pub(crate) fn seek_position(base: u32, offset: i32) -> Result<u32, FsErr> {
+    let pos = i64::from(base) + i64::from(offset);
+
+    if pos < 0 {
+        return Err(FsErr::NegativeSeek);
     }
+
+    u32::try_from(pos).map_err(|_| FsErr::OutOfRange)
 }
 
-impl <'stream, 'bd> Seek for Stream<'stream, 'bd> {
+impl <'stream, 'bd, const N: usize> Seek for Stream<'stream, 'bd, N> {
+    /// Seeks within the region, `End` is the end of the fixed region or of
+    /// the last cluster of the chain.
     fn seek(&mut self, pos: SeekFrom) -> Result<u32, FsErr> {
         let new_pos = match pos {
             SeekFrom::Start(start) => start,
-            SeekFrom::Current(current) => {
-                if (self.global_offset as i64) + (current as i64) < 0 {
-                    return Err(FsErr::NegativeSeek);
+            SeekFrom::Current(current) => seek_position(self.global_offset, current)?,
+            SeekFrom::End(end) => seek_position(self.region_len()?, end)?,
+        };
+
+        match self.region {
+            Region::Chain => {
+                let cluster_size = self.fs.cluster_size;
+
+                if new_pos != 0 && new_pos % cluster_size == 0 {
+                    // the end of a cluster rather than the start of the next
+                    // one, which may not exist yet
+                    self.cluster = self.fs.table_chain_skip(self.first_cluster, new_pos / cluster_size - 1)?;
+                    self.sector = self.fs.sectors_in_cluster - 1;
+                    self.offset = self.fs.sector_size as usize;
+                } else {
+                    self.cluster = self.fs.table_chain_skip(self.first_cluster, new_pos / cluster_size)?;
+                    self.sector = (new_pos % cluster_size) / self.fs.sector_size;
+                    self.offset = (new_pos % self.fs.sector_size) as usize;
+                }
+            },
+            Region::Fixed { size, .. } => {
+                if new_pos > size {
+                    return Err(FsErr::OutOfRange);
                 }
 
-                ((self.global_offset as i32) + current) as u32
+                self.sector = new_pos / self.fs.sector_size;
+                self.offset = (new_pos % self.fs.sector_size) as usize;
             },
-            SeekFrom::End(end) => todo!(),
-        };
+        }
 
-        self.cluster = self.fs.table_chain_skip(self.first_cluster, new_pos / self.fs.cluster_size)?;
-        self.sector = (new_pos % self.fs.cluster_size) / self.fs.sector_size;
-        self.offset = (new_pos % self.fs.sector_size) as usize;
         self.global_offset = new_pos;
         Ok(self.global_offset)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,121 @@
+
+/// Date and time as stored in directory entries, FAT has no notion of time
+/// zones so this is whatever local time the writer used.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug)]
+pub struct DateTime {
+    /// 1980 to 2107
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+/// Source of the current time, a real time clock on devices that have one.
+pub trait TimeProvider {
+    fn now(&self) -> DateTime;
+}
+
+/// When reads stamp the last access date of a file.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum AccessTime {
+    Always,
+    /// Only when the stored access date is older than the modification date
+    /// or more than a day old, saves most writes on read-only workloads.
+    #[default]
+    Relatime,
+    /// noatime, reads never write.
+    Never,
+}
+
+impl DateTime {
+    /// Start of the FAT epoch, used when no clock is available.
+    pub const EPOCH: Self = Self { year: 1980, month: 1, day: 1, hour: 0, minute: 0, second: 0, millisecond: 0 };
+
+    /// Decodes the packed date and time words, `centis` is the 10ms count
+    /// only the creation time has. A zero date means the field is not set.
+    pub fn decode(date: u16, time: u16, centis: u8) -> Option<Self> {
+        if date == 0 {
+            return None;
+        }
+
+        // creation time carries the odd second in its 10ms count
+        let centis = core::cmp::min(centis, 199);
+
+        Some(Self {
+            year: 1980 + (date >> 9),
+            month: ((date >> 5) & 0x0f) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3f) as u8,
+            second: ((time & 0x1f) * 2) as u8 + centis / 100,
+            millisecond: u16::from(centis % 100) * 10,
+        })
+    }
+
+    /// Packs into the date and time words and the 10ms count, years outside
+    /// of the FAT range are clamped.
+    pub fn encode(&self) -> (u16, u16, u8) {
+        let year = self.year.clamp(1980, 2107) - 1980;
+        let date = (year << 9) | (u16::from(self.month) << 5) | u16::from(self.day);
+        let time = (u16::from(self.hour) << 11) | (u16::from(self.minute) << 5) | u16::from(self.second / 2);
+        let centis = (self.second % 2) * 100 + (self.millisecond / 10) as u8;
+
+        (date, time, centis)
+    }
+
+    /// Days since 1980-01-01.
+    pub fn days(&self) -> u32 {
+        const DAYS_BEFORE_MONTH: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+
+        let year = u32::from(self.year.max(1980));
+        let month = usize::from(self.month.clamp(1, 12));
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        // leap days of the years before, 1980 itself is a leap year
+        let leap_days = (year - 1977) / 4 - (year - 1901) / 100 + (year - 1601) / 400;
+        let leap_day = if leap && month > 2 { 1 } else { 0 };
+
+        (year - 1980) * 365 + leap_days + DAYS_BEFORE_MONTH[month - 1] + leap_day + u32::from(self.day.max(1)) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let time = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 59, millisecond: 990 };
+        let (date, packed, centis) = time.encode();
+        assert_eq!(centis, 199);
+        assert_eq!(DateTime::decode(date, packed, centis), Some(time));
+
+        // without the 10ms count the odd second is lost
+        let decoded = DateTime::decode(date, packed, 0).unwrap();
+        assert_eq!((decoded.second, decoded.millisecond), (58, 0));
+
+        assert_eq!(DateTime::EPOCH.encode(), (0x21, 0, 0));
+        assert_eq!(DateTime::decode(0x21, 0, 0), Some(DateTime::EPOCH));
+        assert_eq!(DateTime::decode(0, 0, 0), None);
+    }
+
+    #[test]
+    fn encode_clamps_year() {
+        let time = DateTime { year: 1970, ..DateTime::EPOCH };
+        assert_eq!(time.encode(), DateTime::EPOCH.encode());
+
+        let time = DateTime { year: 2200, ..DateTime::EPOCH };
+        assert_eq!(DateTime::decode(time.encode().0, 0, 0).unwrap().year, 2107);
+    }
+
+    #[test]
+    fn days() {
+        assert_eq!(DateTime::EPOCH.days(), 0);
+        assert_eq!(DateTime { year: 1980, month: 3, day: 1, ..DateTime::EPOCH }.days(), 60);
+        assert_eq!(DateTime { year: 1981, ..DateTime::EPOCH }.days(), 366);
+        assert_eq!(DateTime { year: 2000, month: 3, day: 1, ..DateTime::EPOCH }.days(), 7365);
+        assert_eq!(DateTime { year: 2100, month: 3, day: 1, ..DateTime::EPOCH }.days(), 43889);
+    }
+}
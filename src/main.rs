@@ -1,5 +1,3 @@
-mod fs;
-
 fn main()
 {
 